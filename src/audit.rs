@@ -0,0 +1,54 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Buffered lines go to disk at least this often, a crash loses at most this much
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Appends lines to a file, moving it to FILE.1 once it grows past max_bytes so a long
+// session keeps at most twice that on disk
+pub struct AuditLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    written: u64,
+    max_bytes: u64,
+    last_flush: Instant,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            written,
+            max_bytes,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.file.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, rotated)?;
+        self.file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+// Without dbus only x11_profiles sends commands, if even that is built
+#![cfg_attr(not(feature = "tokey_ipc"), allow(dead_code))]
+
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use std::os::unix::io::RawFd;
+use std::sync::mpsc;
+
+// Requests from other threads that have to be carried out on the input thread
+pub enum Command {
+    Reload,
+    // Only the top level [keymap] out of the config file
+    ReloadKeymap,
+    // Same as pressing pause_key
+    TogglePause,
+    SetPaused(bool),
+    // Whether a source key is mapped at all, in every layer
+    SetMappingEnabled(u16, bool),
+    // Hand the keyboards back to the system or take them again, unlike pausing nothing reaches tokey
+    SetGrabbed(bool),
+    // The system woke up from sleep, devices may have come back under new nodes
+    Resumed,
+    // WM_CLASS of the newly focused window, empty if it has none
+    #[cfg(feature = "x11_profiles")]
+    ActiveWindow(String),
+}
+
+// Every send also bumps an eventfd so the input thread's epoll wakes up
+#[derive(Clone)]
+pub struct CommandSender {
+    tx: mpsc::Sender<Command>,
+    wake_fd: RawFd,
+}
+
+impl CommandSender {
+    pub fn send(&self, command: Command) {
+        if self.tx.send(command).is_ok() {
+            let _ = nix::unistd::write(self.wake_fd, &1u64.to_ne_bytes());
+        }
+    }
+}
+
+pub struct CommandReceiver {
+    rx: mpsc::Receiver<Command>,
+    wake_fd: RawFd,
+}
+
+impl CommandReceiver {
+    // Readable whenever commands are pending, meant to be added to an epoll set
+    pub fn fd(&self) -> RawFd {
+        self.wake_fd
+    }
+    
+    pub fn drain(&self) -> Vec<Command> {
+        // Reset the eventfd counter before taking the commands so none are missed
+        let mut counter = [0u8; 8];
+        let _ = nix::unistd::read(self.wake_fd, &mut counter);
+        self.rx.try_iter().collect()
+    }
+}
+
+pub fn channel() -> nix::Result<(CommandSender, CommandReceiver)> {
+    let wake_fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+    let (tx, rx) = mpsc::channel();
+    Ok((CommandSender { tx, wake_fd }, CommandReceiver { rx, wake_fd }))
+}
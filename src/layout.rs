@@ -0,0 +1,204 @@
+use evdev::Key;
+
+// Tables for expanding text macros into key presses. Adding a layout only takes a table and
+// an entry in LAYOUTS, dead keys are left out since they don't type anything on their own
+
+// A key and what it types plain, with shift and with AltGr, in that order
+type Row = (Key, &'static str);
+
+pub struct Layout {
+    pub name: &'static str,
+    rows: &'static [Row],
+}
+
+pub const US: Layout = Layout { name: "us", rows: US_ROWS };
+
+const LAYOUTS: &[Layout] = &[
+    US,
+    Layout { name: "uk", rows: UK_ROWS },
+    Layout { name: "de", rows: DE_ROWS },
+];
+
+// Keys that type the same character everywhere
+const COMMON: &[(Key, char)] = &[
+    (Key::KEY_SPACE, ' '),
+    (Key::KEY_TAB, '\t'),
+    (Key::KEY_ENTER, '\n'),
+];
+
+// Modifiers held for each level of a row
+const LEVELS: [&[Key]; 3] = [&[], &[Key::KEY_LEFTSHIFT], &[Key::KEY_RIGHTALT]];
+
+pub fn find(name: &str) -> Option<&'static Layout> {
+    LAYOUTS.iter().find(|layout| layout.name.eq_ignore_ascii_case(name))
+}
+
+pub fn names() -> Vec<&'static str> {
+    LAYOUTS.iter().map(|layout| layout.name).collect()
+}
+
+impl Layout {
+    // The chord that types `c`, e.g. [KEY_LEFTSHIFT, KEY_2] for '@' on a US layout
+    pub fn chord_for(&self, c: char) -> Option<Vec<u16>> {
+        if let Some((key, _)) = COMMON.iter().find(|(_, ch)| *ch == c) {
+            return Some(vec![key.code()]);
+        }
+        self.rows.iter().find_map(|(key, chars)| {
+            let level = chars.chars().position(|ch| ch == c)?;
+            let mut chord: Vec<u16> = LEVELS[level].iter().map(|m| m.code()).collect();
+            chord.push(key.code());
+            Some(chord)
+        })
+    }
+}
+
+const US_ROWS: &[Row] = &[
+    (Key::KEY_GRAVE, "`~"),
+    (Key::KEY_1, "1!"),
+    (Key::KEY_2, "2@"),
+    (Key::KEY_3, "3#"),
+    (Key::KEY_4, "4$"),
+    (Key::KEY_5, "5%"),
+    (Key::KEY_6, "6^"),
+    (Key::KEY_7, "7&"),
+    (Key::KEY_8, "8*"),
+    (Key::KEY_9, "9("),
+    (Key::KEY_0, "0)"),
+    (Key::KEY_MINUS, "-_"),
+    (Key::KEY_EQUAL, "=+"),
+    (Key::KEY_Q, "qQ"),
+    (Key::KEY_W, "wW"),
+    (Key::KEY_E, "eE"),
+    (Key::KEY_R, "rR"),
+    (Key::KEY_T, "tT"),
+    (Key::KEY_Y, "yY"),
+    (Key::KEY_U, "uU"),
+    (Key::KEY_I, "iI"),
+    (Key::KEY_O, "oO"),
+    (Key::KEY_P, "pP"),
+    (Key::KEY_LEFTBRACE, "[{"),
+    (Key::KEY_RIGHTBRACE, "]}"),
+    (Key::KEY_BACKSLASH, "\\|"),
+    (Key::KEY_A, "aA"),
+    (Key::KEY_S, "sS"),
+    (Key::KEY_D, "dD"),
+    (Key::KEY_F, "fF"),
+    (Key::KEY_G, "gG"),
+    (Key::KEY_H, "hH"),
+    (Key::KEY_J, "jJ"),
+    (Key::KEY_K, "kK"),
+    (Key::KEY_L, "lL"),
+    (Key::KEY_SEMICOLON, ";:"),
+    (Key::KEY_APOSTROPHE, "'\""),
+    (Key::KEY_Z, "zZ"),
+    (Key::KEY_X, "xX"),
+    (Key::KEY_C, "cC"),
+    (Key::KEY_V, "vV"),
+    (Key::KEY_B, "bB"),
+    (Key::KEY_N, "nN"),
+    (Key::KEY_M, "mM"),
+    (Key::KEY_COMMA, ",<"),
+    (Key::KEY_DOT, ".>"),
+    (Key::KEY_SLASH, "/?"),
+];
+
+const UK_ROWS: &[Row] = &[
+    (Key::KEY_GRAVE, "`¬"),
+    (Key::KEY_1, "1!"),
+    (Key::KEY_2, "2\""),
+    (Key::KEY_3, "3£"),
+    (Key::KEY_4, "4$€"),
+    (Key::KEY_5, "5%"),
+    (Key::KEY_6, "6^"),
+    (Key::KEY_7, "7&"),
+    (Key::KEY_8, "8*"),
+    (Key::KEY_9, "9("),
+    (Key::KEY_0, "0)"),
+    (Key::KEY_MINUS, "-_"),
+    (Key::KEY_EQUAL, "=+"),
+    (Key::KEY_Q, "qQ"),
+    (Key::KEY_W, "wW"),
+    (Key::KEY_E, "eE"),
+    (Key::KEY_R, "rR"),
+    (Key::KEY_T, "tT"),
+    (Key::KEY_Y, "yY"),
+    (Key::KEY_U, "uU"),
+    (Key::KEY_I, "iI"),
+    (Key::KEY_O, "oO"),
+    (Key::KEY_P, "pP"),
+    (Key::KEY_LEFTBRACE, "[{"),
+    (Key::KEY_RIGHTBRACE, "]}"),
+    (Key::KEY_A, "aA"),
+    (Key::KEY_S, "sS"),
+    (Key::KEY_D, "dD"),
+    (Key::KEY_F, "fF"),
+    (Key::KEY_G, "gG"),
+    (Key::KEY_H, "hH"),
+    (Key::KEY_J, "jJ"),
+    (Key::KEY_K, "kK"),
+    (Key::KEY_L, "lL"),
+    (Key::KEY_SEMICOLON, ";:"),
+    (Key::KEY_APOSTROPHE, "'@"),
+    (Key::KEY_BACKSLASH, "#~"),
+    (Key::KEY_102ND, "\\|"),
+    (Key::KEY_Z, "zZ"),
+    (Key::KEY_X, "xX"),
+    (Key::KEY_C, "cC"),
+    (Key::KEY_V, "vV"),
+    (Key::KEY_B, "bB"),
+    (Key::KEY_N, "nN"),
+    (Key::KEY_M, "mM"),
+    (Key::KEY_COMMA, ",<"),
+    (Key::KEY_DOT, ".>"),
+    (Key::KEY_SLASH, "/?"),
+];
+
+// QWERTZ, ^ and the accents are dead keys
+const DE_ROWS: &[Row] = &[
+    (Key::KEY_1, "1!"),
+    (Key::KEY_2, "2\"²"),
+    (Key::KEY_3, "3§³"),
+    (Key::KEY_4, "4$"),
+    (Key::KEY_5, "5%"),
+    (Key::KEY_6, "6&"),
+    (Key::KEY_7, "7/{"),
+    (Key::KEY_8, "8(["),
+    (Key::KEY_9, "9)]"),
+    (Key::KEY_0, "0=}"),
+    (Key::KEY_MINUS, "ß?\\"),
+    (Key::KEY_Q, "qQ@"),
+    (Key::KEY_W, "wW"),
+    (Key::KEY_E, "eE€"),
+    (Key::KEY_R, "rR"),
+    (Key::KEY_T, "tT"),
+    (Key::KEY_Y, "zZ"),
+    (Key::KEY_U, "uU"),
+    (Key::KEY_I, "iI"),
+    (Key::KEY_O, "oO"),
+    (Key::KEY_P, "pP"),
+    (Key::KEY_LEFTBRACE, "üÜ"),
+    (Key::KEY_RIGHTBRACE, "+*~"),
+    (Key::KEY_A, "aA"),
+    (Key::KEY_S, "sS"),
+    (Key::KEY_D, "dD"),
+    (Key::KEY_F, "fF"),
+    (Key::KEY_G, "gG"),
+    (Key::KEY_H, "hH"),
+    (Key::KEY_J, "jJ"),
+    (Key::KEY_K, "kK"),
+    (Key::KEY_L, "lL"),
+    (Key::KEY_SEMICOLON, "öÖ"),
+    (Key::KEY_APOSTROPHE, "äÄ"),
+    (Key::KEY_BACKSLASH, "#'"),
+    (Key::KEY_102ND, "<>|"),
+    (Key::KEY_Z, "yY"),
+    (Key::KEY_X, "xX"),
+    (Key::KEY_C, "cC"),
+    (Key::KEY_V, "vV"),
+    (Key::KEY_B, "bB"),
+    (Key::KEY_N, "nN"),
+    (Key::KEY_M, "mMµ"),
+    (Key::KEY_COMMA, ",;"),
+    (Key::KEY_DOT, ".:"),
+    (Key::KEY_SLASH, "-_"),
+];
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+    
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+// Warnings and errors end up here instead of stderr while capturing
+static CAPTURED: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+// Reads the level from RUST_LOG, either "debug" or "tokey=debug"
+pub fn init() {
+    if let Ok(filter) = std::env::var("RUST_LOG") {
+        let level = filter
+            .split(',')
+            .filter_map(|part| match part.split_once('=') {
+                Some((target, level)) if target.trim() == "tokey" => Level::parse(level),
+                Some(_) => None,
+                None => Level::parse(part),
+            })
+            .next_back();
+        if let Some(level) = level {
+            MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+        }
+    }
+}
+
+// Make sure messages at `level` are shown, whatever RUST_LOG says
+pub fn raise_level(level: Level) {
+    MAX_LEVEL.fetch_max(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, args: fmt::Arguments) {
+    if level <= Level::Warn {
+        if let Some(captured) = CAPTURED.lock().unwrap().as_mut() {
+            captured.push(args.to_string());
+            return;
+        }
+    }
+    eprintln!("[{}] {}", level.name(), args);
+}
+
+// Collect warnings and errors for captured() instead of printing them
+pub fn capture() {
+    raise_level(Level::Warn);
+    *CAPTURED.lock().unwrap() = Some(Vec::new());
+}
+
+// Everything captured since capture(), which stops capturing
+pub fn captured() -> Vec<String> {
+    CAPTURED.lock().unwrap().take().unwrap_or_default()
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::logging::enabled($level) {
+            $crate::logging::log($level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log!($crate::logging::Level::Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::logging::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!($crate::logging::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!($crate::logging::Level::Debug, $($arg)*) };
+}
+
+#[allow(unused_macros)]
+macro_rules! trace {
+    ($($arg:tt)*) => { log!($crate::logging::Level::Trace, $($arg)*) };
+}
@@ -7,65 +7,287 @@
 use evdev::InputEvent;
 use evdev::InputEventKind;
 use evdev::Key;
-use evdev::uinput::VirtualDevice;
+use evdev::RelativeAxisType;
 use nix::{
     fcntl::{FcntlArg, OFlag},
-    sys::epoll,
+    sys::{epoll, signal},
 };
-use serde_derive::Deserialize;
-use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::Write;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::time::Instant;
-use toml;
 
+#[macro_use]
+mod logging;
+mod audit;
+mod command;
+mod layout;
+mod replay;
+#[cfg(feature = "systemd")]
+mod systemd;
 #[cfg(feature = "tokey_ipc")]
 mod tokey_ipc;
+#[cfg(not(feature = "tokey_ipc"))]
+#[path = "no_ipc.rs"]
+mod tokey_ipc;
+mod uinput;
+mod watchdog;
+#[cfg(feature = "x11_profiles")]
+mod x11_profiles;
+
+use command::Command;
+use uinput::VirtualDevice;
 
 extern crate xdg;
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
 enum State {
     IDLE,
     DECIDE,
     SHIFT,
+    // A lone fn_key tap started a leader sequence from [sequences]
+    SEQUENCE,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::IDLE => "IDLE",
+            State::DECIDE => "DECIDE",
+            State::SHIFT => "SHIFT",
+            State::SEQUENCE => "SEQUENCE",
+        }
+    }
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum KeyState {
-    INVALID = -1,
     UP = 0,
     DOWN = 1,
     REPEAT = 2,
 }
 
-impl Into<KeyState> for i32 {
-    fn into(self) -> KeyState {
-        match self {
-            -1 => KeyState::INVALID,
-            0 => KeyState::UP,
-            1 => KeyState::DOWN,
-            2 => KeyState::REPEAT,
-            _ => KeyState::INVALID,
+impl TryFrom<i32> for KeyState {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(KeyState::UP),
+            1 => Ok(KeyState::DOWN),
+            2 => Ok(KeyState::REPEAT),
+            _ => Err(format!("Unknown key value {}", value)),
         }
     }
 }
 
-#[derive(Deserialize)]
+fn is_down(value: i32) -> bool {
+    value == KeyState::DOWN as i32
+}
+
+#[derive(Deserialize, Serialize)]
 struct Config {
+    version: Option<i64>,
     device_name: toml::Value,
-    mode_switch_timeout: toml::Value,
+    mode_switch_timeout: Option<toml::Value>,
     fn_key: toml::Value,
     pause_key: toml::Value,
     keymap: toml::value::Table,
+    layers: Option<toml::value::Table>,
+    emit_syn: Option<bool>,
+    keymap_conflicts: Option<String>,
+    virtual_device_name: Option<String>,
+    startup_grace_ms: Option<u64>,
+    reconnect: Option<bool>,
+    double_tap_ms: Option<u64>,
+    scroll_interval_ms: Option<u64>,
+    layout: Option<String>,
+    passthrough_keys: Option<Vec<toml::Value>>,
+    oneshot: Option<bool>,
+    aliases: Option<toml::value::Table>,
+    remap_prefn_release: Option<bool>,
+    inter_key_delay_us: Option<u64>,
+    emit_scancode: Option<bool>,
+    sequences: Option<toml::value::Table>,
+    sequence_timeout_ms: Option<u64>,
+    resolve_on_down: Option<bool>,
+    event_buffer_size: Option<usize>,
+    caps_word_end: Option<Vec<toml::Value>>,
+    invert: Option<bool>,
+    fn_tap_key: Option<toml::Value>,
+    debounce_ms: Option<u64>,
+    passthrough_fn_hold: Option<bool>,
+    watchdog_ms: Option<u64>,
+    min_hold_ms: Option<u64>,
+    key_double_tap_ms: Option<u64>,
+    preserve_timestamps: Option<bool>,
+    lock_idle_timeout_ms: Option<u64>,
+    escape_combo: Option<toml::Value>,
+    escape_hold_ms: Option<u64>,
+    log_file: Option<String>,
+    handle_suspend: Option<bool>,
+    adaptive_timeout_ms: Option<toml::Value>,
+    virtual_autorepeat: Option<bool>,
+    pointer_speed: Option<i32>,
+    pointer_max_speed: Option<i32>,
+    pointer_accel_ms: Option<u64>,
+    device_keymaps: Option<toml::value::Table>,
+    global_keymap: Option<toml::value::Table>,
+    #[cfg(feature = "x11_profiles")]
+    profiles: Option<toml::value::Table>,
+    // Options this tokey doesn't know, e.g. from a newer version, warned about and ignored
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::value::Table,
+}
+
+impl Config {
+    // The config as tokey ends up using it, options that are off unless set stay unset
+    fn with_defaults(self) -> Config {
+        let table = || Some(toml::value::Table::new());
+        Config {
+            version: self.version.or(Some(0)),
+            mode_switch_timeout: self
+                .mode_switch_timeout
+                .or(Some(toml::Value::Integer(DEFAULT_MODE_SWITCH_TIMEOUT_MS as i64))),
+            layers: self.layers.or_else(table),
+            emit_syn: self.emit_syn.or(Some(false)),
+            keymap_conflicts: self.keymap_conflicts.or_else(|| Some("warn".to_string())),
+            virtual_device_name: self
+                .virtual_device_name
+                .or_else(|| Some(DEFAULT_VIRTUAL_DEVICE_NAME.to_string())),
+            startup_grace_ms: self.startup_grace_ms.or(Some(DEFAULT_STARTUP_GRACE_MS)),
+            reconnect: self.reconnect.or(Some(true)),
+            scroll_interval_ms: self.scroll_interval_ms.or(Some(DEFAULT_SCROLL_INTERVAL_MS)),
+            layout: self.layout.or_else(|| Some(layout::US.name.to_string())),
+            passthrough_keys: self.passthrough_keys.or_else(|| Some(Vec::new())),
+            oneshot: self.oneshot.or(Some(false)),
+            aliases: self.aliases.or_else(table),
+            remap_prefn_release: self.remap_prefn_release.or(Some(false)),
+            inter_key_delay_us: self.inter_key_delay_us.or(Some(0)),
+            emit_scancode: self.emit_scancode.or(Some(false)),
+            sequences: self.sequences.or_else(table),
+            sequence_timeout_ms: self.sequence_timeout_ms.or(Some(DEFAULT_SEQUENCE_TIMEOUT_MS)),
+            resolve_on_down: self.resolve_on_down.or(Some(false)),
+            event_buffer_size: self.event_buffer_size.or(Some(DEFAULT_EVENT_BUFFER_SIZE)),
+            caps_word_end: self.caps_word_end.or_else(|| {
+                Some(DEFAULT_CAPS_WORD_END.iter().map(|key| format!("{:?}", key).into()).collect())
+            }),
+            invert: self.invert.or(Some(false)),
+            fn_tap_key: self.fn_tap_key.or_else(|| Some(self.fn_key.clone())),
+            passthrough_fn_hold: self.passthrough_fn_hold.or(Some(false)),
+            key_double_tap_ms: self.key_double_tap_ms.or(Some(DEFAULT_KEY_DOUBLE_TAP_MS)),
+            preserve_timestamps: self.preserve_timestamps.or(Some(false)),
+            escape_combo: self.escape_combo.or_else(|| Some(DEFAULT_ESCAPE_COMBO.into())),
+            escape_hold_ms: self.escape_hold_ms.or(Some(DEFAULT_ESCAPE_HOLD_MS)),
+            handle_suspend: self.handle_suspend.or(Some(false)),
+            virtual_autorepeat: self.virtual_autorepeat.or(Some(true)),
+            pointer_speed: self.pointer_speed.or(Some(DEFAULT_POINTER_SPEED)),
+            pointer_max_speed: self.pointer_max_speed.or(Some(DEFAULT_POINTER_MAX_SPEED)),
+            pointer_accel_ms: self.pointer_accel_ms.or(Some(DEFAULT_POINTER_ACCEL_MS)),
+            device_keymaps: self.device_keymaps.or_else(table),
+            global_keymap: self.global_keymap.or_else(table),
+            #[cfg(feature = "x11_profiles")]
+            profiles: self.profiles.or_else(table),
+            ..self
+        }
+    }
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Bumped whenever options are added, configs with an older version hear about them on startup
+const CONFIG_VERSION: i64 = 21;
+// Options added in each config version, configs without a version are 0
+const CONFIG_CHANGES: &[(i64, &[&str])] = &[
+    (
+        1,
+        &[
+            "layers", "emit_syn", "keymap_conflicts", "virtual_device_name", "startup_grace_ms",
+            "reconnect", "double_tap_ms", "scroll_interval_ms", "layout", "passthrough_keys", "oneshot",
+            "aliases", "remap_prefn_release", "inter_key_delay_us", "emit_scancode", "sequences",
+            "sequence_timeout_ms", "profiles",
+        ],
+    ),
+    (2, &["resolve_on_down"]),
+    (3, &["event_buffer_size"]),
+    (4, &["device_keymaps"]),
+    (5, &["caps_word_end"]),
+    (6, &["invert"]),
+    (7, &["fn_tap_key"]),
+    (8, &["debounce_ms"]),
+    (9, &["pointer_speed", "pointer_max_speed", "pointer_accel_ms"]),
+    (10, &["passthrough_fn_hold"]),
+    (11, &["watchdog_ms"]),
+    (12, &["min_hold_ms"]),
+    (13, &["key_double_tap_ms"]),
+    (14, &["preserve_timestamps"]),
+    (15, &["lock_idle_timeout_ms"]),
+    (16, &["global_keymap"]),
+    (17, &["escape_combo", "escape_hold_ms"]),
+    (18, &["log_file"]),
+    (19, &["handle_suspend"]),
+    (20, &["adaptive_timeout_ms"]),
+    (21, &["virtual_autorepeat"]),
+];
+const CONF_FILE_NAME: &str = "conf.toml";
+// -c - reads the config from stdin
+const STDIN_CONF_PATH: &str = "-";
+const DEFAULT_VIRTUAL_DEVICE_NAME: &str = "tokey-kbd";
+// uinput names are limited to 80 bytes including the terminating nul
+const MAX_VIRTUAL_DEVICE_NAME_LEN: usize = 78;
+const DEFAULT_STARTUP_GRACE_MS: u64 = 1000;
+const DEFAULT_SCROLL_INTERVAL_MS: u64 = 50;
+const DEFAULT_SEQUENCE_TIMEOUT_MS: u64 = 1000;
+// Pointer keys move this many units per step when pressed, speeding up to max over accel ms
+const DEFAULT_POINTER_SPEED: i32 = 2;
+const DEFAULT_POINTER_MAX_SPEED: i32 = 20;
+const DEFAULT_POINTER_ACCEL_MS: u64 = 1000;
+// Steps are sent much more often than scrolls so the pointer glides
+const POINTER_INTERVAL: Duration = Duration::from_millis(10);
+const DEFAULT_MODE_SWITCH_TIMEOUT_MS: u64 = 200;
+const DEFAULT_KEY_DOUBLE_TAP_MS: u64 = 200;
+// Held this long, the combo makes tokey let go of the keyboard and exit whatever the keymap does
+const DEFAULT_ESCAPE_COMBO: &str = "KEY_LEFTCTRL+KEY_LEFTALT+KEY_BACKSPACE";
+const DEFAULT_ESCAPE_HOLD_MS: u64 = 2000;
+// log_file moves to log_file.1 past this size
+const LOG_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+// Word boundaries that end caps word
+const DEFAULT_CAPS_WORD_END: &[Key] = &[
+    Key::KEY_SPACE, Key::KEY_ENTER, Key::KEY_KPENTER, Key::KEY_TAB, Key::KEY_ESC, Key::KEY_DOT,
+    Key::KEY_COMMA, Key::KEY_SEMICOLON, Key::KEY_APOSTROPHE, Key::KEY_SLASH,
+];
+// Lower timeouts shift on practically every key press after fn_key
+const MIN_MODE_SWITCH_TIMEOUT_MS: u64 = 10;
+// With adaptive_timeout_ms, the timeout is this many times the average time between recent
+// key presses. Pauses longer than ADAPTIVE_MAX_INTERVAL count as that, so one break doesn't
+// throw the average off
+const ADAPTIVE_TIMEOUT_FACTOR: u32 = 2;
+const ADAPTIVE_SAMPLES: usize = 8;
+const ADAPTIVE_MAX_INTERVAL: Duration = Duration::from_secs(1);
+// Most keys that can be held while the fn key is still undecided
+const DEFAULT_EVENT_BUFFER_SIZE: usize = 10;
+// KEY_MAX, the kernel doesn't take higher keycodes
+const MAX_KEYCODE: i64 = 0x2ff;
+// Kept in the XDG data dir so pausing survives restarts
+const PAUSED_FILE_NAME: &str = "paused";
+// How often to look for an unplugged device coming back
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// A busy device may be let go by whoever holds it, e.g. a previous tokey still exiting.
+// The delay doubles after every attempt
+const GRAB_ATTEMPTS: u32 = 5;
+const GRAB_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 macro_rules! default_conf {
     () => {
-        r#"device_name = ""
+        r#"version = 21
+device_name = ""
 mode_switch_timeout = 200
 fn_key = "KEY_SPACE"
 pause_key = "KEY_RIGHTALT"
@@ -91,336 +313,5899 @@ fn version() {
     exit(0);
 }
 
+// /dev/input/event*, sorted numerically so event10 comes after event9
+fn event_device_paths() -> std::io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir("/dev/input")?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("event"))
+        })
+        .collect();
+    paths.sort_by_key(|path| {
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.trim_start_matches("event").parse::<u32>().ok())
+    });
+    Ok(paths)
+}
+
+fn list_devices() {
+    let paths = event_device_paths().unwrap_or_else(|e| {
+        eprintln!("Can't read /dev/input: {}", e);
+        exit(1);
+    });
+
+    for path in paths {
+        match evdev::Device::open(&path) {
+            Ok(dev) => println!("{}\t{}", path.display(), dev.name().unwrap_or("<unnamed>")),
+            Err(e) => println!("{}\t<{}>", path.display(), e),
+        }
+    }
+    exit(0);
+}
+
+fn print_default_config() {
+    print!(default_conf!());
+    exit(0);
+}
+
 fn help() {
     println!(
-        r#"Usage: tokey [OPTION]... [FILE]...
-Add Description of tokey
+        r#"Usage: tokey [OPTION]...
+Map keys to other keys while a function key is held
 
-  -c,            specify a custom configuration file
-  -v, --help     display this help and exit
-      --version  output version information and exit
+  -c, --config FILE         use FILE instead of ~/.config/tokey/conf.toml, - reads stdin
+      --config-dir DIR      use DIR/conf.toml, creating it with the defaults if missing
+      --list-devices        list input devices and their paths and exit
+      --monitor             log what would be emitted without grabbing the keyboard
+      --format FORMAT       with --monitor or --replay, print text (the default) or json,
+                              one object per line on stdout
+      --print-default-config  print the default configuration file and exit
+      --show-config         print the configuration in use, defaults included, and exit
+      --check FILE          check the configuration in FILE, print OK or every problem
+                              found and exit, 1 if there were any
+      --replay FILE         run the events recorded in FILE (evemu-record output or
+                              type,code,value,seconds lines) and log what would be emitted
+  -h, --help                display this help and exit
+  -v, --version             output version information and exit
 
-Full documentation <https://www.github.com/chronotab/tokey>
-    "#
+Full documentation <https://www.github.com/chronotab/tokey>"#
     );
-    exit(1);
+    exit(0);
 }
 
-fn get_config() -> Config {
-    let args: Vec<String> = std::env::args().collect();
-    let mut conf_contents = String::new();
+// What the command line asks tokey to do besides running
+#[derive(Debug, PartialEq)]
+enum Action {
+    Run,
+    Help,
+    Version,
+    ListDevices,
+    PrintDefaultConfig,
+    ShowConfig,
+    Check,
+}
 
-    match &args.len() {
-        // no Arguments passed
-        1 => {
-            let xdg_dirs = xdg::BaseDirectories::with_prefix("tokey").unwrap();
-            let conf_filename_opt = xdg_dirs.find_config_file("conf.toml");
-            if conf_filename_opt.is_none() {
-                let conf_path = xdg_dirs
-                    .place_config_file("conf.toml")
-                    .expect("Can't create config directory");
-                let mut conf_file = std::fs::File::create(conf_path).unwrap();
-                write!(&mut conf_file, default_conf!()).expect("Can't write config file");
-            }
+#[derive(Debug, PartialEq)]
+struct Args {
+    action: Action,
+    conf_path: Option<PathBuf>,
+    // Where conf.toml is looked for and created instead of the XDG config dir
+    conf_dir: Option<PathBuf>,
+    // Can be combined with everything else
+    monitor: bool,
+    // --format json, print decisions as JSON lines instead of logging them
+    json: bool,
+    // Events to feed the state machine instead of a device
+    replay: Option<PathBuf>,
+}
 
-            conf_contents = std::fs::read_to_string(conf_filename_opt.unwrap()).unwrap();
-        }
-        2 => {
-            if &args[1] == "-v" {
-                version();
-            } else {
-                help();
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        action: Action::Run,
+        conf_path: None,
+        conf_dir: None,
+        monitor: false,
+        json: false,
+        replay: None,
+    };
+    while let Some(arg) = args.next() {
+        let action = match arg.as_str() {
+            "-c" | "--config" => {
+                let path = args.next().ok_or_else(|| format!("{} needs a FILE", arg))?;
+                parsed.conf_path = Some(PathBuf::from(path));
+                continue;
+            }
+            "--config-dir" => {
+                let dir = args.next().ok_or_else(|| format!("{} needs a DIR", arg))?;
+                parsed.conf_dir = Some(PathBuf::from(dir));
+                continue;
+            }
+            "--monitor" => {
+                parsed.monitor = true;
+                continue;
+            }
+            "--format" => {
+                parsed.json = match args.next().as_deref() {
+                    Some("text") => false,
+                    Some("json") => true,
+                    Some(format) => return Err(format!("unknown format {}, use text or json", format)),
+                    None => return Err(format!("{} needs a FORMAT", arg)),
+                };
+                continue;
+            }
+            "--replay" => {
+                let path = args.next().ok_or_else(|| format!("{} needs a FILE", arg))?;
+                parsed.replay = Some(PathBuf::from(path));
+                continue;
             }
+            "-h" | "--help" => Action::Help,
+            "-v" | "--version" => Action::Version,
+            "--list-devices" => Action::ListDevices,
+            "--print-default-config" => Action::PrintDefaultConfig,
+            "--show-config" => Action::ShowConfig,
+            "--check" => {
+                let path = args.next().ok_or_else(|| format!("{} needs a FILE", arg))?;
+                parsed.conf_path = Some(PathBuf::from(path));
+                Action::Check
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        };
+        // The first action wins, e.g. --help --version shows help
+        if parsed.action == Action::Run {
+            parsed.action = action;
         }
-        // flag and argument passed
-        3 => match args[1].as_str() {
-            "-c" => {
-                conf_contents = std::fs::read_to_string(&args[2])
-                    .expect("Something went wrong reading the file");
+    }
+    if parsed.conf_path.is_some() && parsed.conf_dir.is_some() {
+        return Err("--config and --config-dir can't be combined".to_string());
+    }
+    if parsed.json && !parsed.monitor && parsed.replay.is_none() {
+        return Err("--format only works with --monitor or --replay".to_string());
+    }
+    Ok(parsed)
+}
+
+fn get_args() -> Args {
+    parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("tokey: {}\nTry 'tokey --help' for more information.", e);
+        exit(1);
+    })
+}
+
+fn get_config(args: &Args) -> (Config, PathBuf) {
+    match args.action {
+        // Needs the config, shown once it's read
+        Action::Run | Action::ShowConfig => {}
+        Action::Check => check(args.conf_path.as_deref().unwrap_or(Path::new(STDIN_CONF_PATH))),
+        Action::Help => help(),
+        Action::Version => version(),
+        Action::ListDevices => list_devices(),
+        Action::PrintDefaultConfig => print_default_config(),
+    }
+
+    let conf_path = match (&args.conf_path, &args.conf_dir) {
+        (Some(path), _) => path.clone(),
+        (None, Some(dir)) => {
+            let path = dir.join(CONF_FILE_NAME);
+            if !path.exists() {
+                std::fs::create_dir_all(dir).expect("Can't create config directory");
+                write_default_config(&path);
             }
-            _ => {
-                help();
+            path
+        }
+        (None, None) => {
+            let xdg_dirs = xdg::BaseDirectories::with_prefix("tokey").unwrap();
+            match xdg_dirs.find_config_file(CONF_FILE_NAME) {
+                Some(path) => path,
+                None => {
+                    let path = xdg_dirs
+                        .place_config_file(CONF_FILE_NAME)
+                        .expect("Can't create config directory");
+                    write_default_config(&path);
+                    path
+                }
             }
-        },
-        _ => {
-            help();
+        }
+    };
+
+    match read_config(&conf_path) {
+        Ok(config) if args.action == Action::ShowConfig => show_config(config, &conf_path),
+        Ok(config) => {
+            check_config_version(config.version.unwrap_or(0));
+            warn_unknown_options(&config);
+            (config, conf_path)
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn show_config(config: Config, conf_path: &Path) -> ! {
+    match toml::Value::try_from(config.with_defaults()) {
+        Ok(config) => {
+            println!("# Read from {}", conf_path.display());
+            print!("{}", config);
+            exit(0);
+        }
+        Err(e) => {
+            eprintln!("Can't show config: {}", e);
+            exit(1);
         }
     }
+}
 
-    toml::from_str::<Config>(conf_contents.as_str()).expect("Error parsing config file")
+// --check, for CI and pre-commit hooks. Doesn't touch any device
+fn check(conf_path: &Path) -> ! {
+    let problems = match read_config(conf_path) {
+        Ok(config) => config_problems(config),
+        Err(e) => vec![e],
+    };
+    if problems.is_empty() {
+        println!("OK");
+        exit(0);
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    exit(1);
 }
 
-fn get_keymap(in_keymap: toml::value::Map<String, toml::Value>) -> HashMap<u16, u16> {
-    let mut keymap: HashMap<u16, u16> = HashMap::new();
-    for kvp in in_keymap.iter() {
-        let k = Key::from_str(kvp.0).expect(format!("Invalid keymap key").as_str());
-        let v_str = kvp
-            .1
-            .as_str()
-            .expect(format!("Couldn't parse keymap value as string").as_str());
-        let v = Key::from_str(v_str).expect(format!("Invalid keymap value").as_str());
-        keymap.insert(k.code(), v.code());
+// Everything tokey would refuse to start over or warn about at startup
+fn config_problems(config: Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    logging::capture();
+    check_config_version(config.version.unwrap_or(0));
+    warn_unknown_options(&config);
+    if config.virtual_device_name.as_ref().is_some_and(|name| name.len() > MAX_VIRTUAL_DEVICE_NAME_LEN) {
+        problems.push(format!(
+            "virtual_device_name can be at most {} bytes long",
+            MAX_VIRTUAL_DEVICE_NAME_LEN
+        ));
+    }
+    let device_names = match &config.device_name {
+        toml::Value::Array(names) => names.iter().collect(),
+        name => vec![name],
+    };
+    for name in device_names.into_iter().filter(|name| !name.is_str()) {
+        problems.push(DeviceError::InvalidName(name.to_string()).to_string());
     }
-    return keymap;
+    let error = Settings::from_config(config).err();
+    // Warnings first, they were logged before whatever stopped from_config
+    let mut found = logging::captured();
+    found.append(&mut problems);
+    found.extend(error);
+    found
 }
 
-fn get_device(mut device_name: String) -> std::io::Result<evdev::Device> {
-    let device: evdev::Device;
-    device_name.retain(|c| c != '"');
+fn config_additions(version: i64) -> Vec<&'static str> {
+    CONFIG_CHANGES
+        .iter()
+        .filter(|(added_in, _)| *added_in > version)
+        .flat_map(|(_, options)| options.iter().copied())
+        .collect()
+}
 
-    if device_name.starts_with("/dev/input/") {
-        device = evdev::Device::open(device_name).unwrap();
-    } else {
-        device = evdev::enumerate()
-            .find(|d| d.name().unwrap().contains(&device_name))
-            .unwrap();
+fn check_config_version(version: i64) {
+    if version > CONFIG_VERSION {
+        warn!(
+            "Config version {} is newer than this tokey understands ({}), some options may be ignored",
+            version, CONFIG_VERSION
+        );
+        return;
     }
+    let added = config_additions(version);
+    if !added.is_empty() {
+        warn!(
+            "Config is version {}, options added since: {}. Set version = {} to silence this",
+            version,
+            added.join(", "),
+            CONFIG_VERSION
+        );
+    }
+}
 
-    let raw_fd = device.as_raw_fd();
-    nix::fcntl::fcntl(raw_fd, FcntlArg::F_SETFL(OFlag::O_RDONLY))?;
+fn warn_unknown_options(config: &Config) {
+    for name in config.unknown.keys() {
+        warn!(
+            "Ignoring unknown option {}, it's either misspelled or from a tokey newer than this one \
+             or built with other features",
+            name
+        );
+    }
+}
 
-    // create epoll handle and attach raw_fd
-    let epoll_fd = epoll::epoll_create1(epoll::EpollCreateFlags::EPOLL_CLOEXEC)?;
-    let mut event = epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, 0);
-    epoll::epoll_ctl(
-        epoll_fd.as_raw_fd(),
-        epoll::EpollOp::EpollCtlAdd,
-        raw_fd,
-        Some(&mut event),
-    )?;
+fn write_default_config(path: &Path) {
+    let mut conf_file = std::fs::File::create(path).unwrap();
+    write!(&mut conf_file, default_conf!()).expect("Can't write config file");
+}
 
-    Ok(device)
+fn read_config(conf_path: &Path) -> Result<Config, String> {
+    if conf_path == Path::new(STDIN_CONF_PATH) {
+        return read_stdin_config(std::io::stdin());
+    }
+    let conf_contents = std::fs::read_to_string(conf_path)
+        .map_err(|e| format!("Can't read config file {}: {}", conf_path.display(), e))?;
+    parse_config(&conf_contents, &format!("config file {}", conf_path.display()))
 }
 
-fn send_key_down(virt_dev: &mut VirtualDevice, code: u16) {
-    send_key(virt_dev, code, KeyState::DOWN);
+// For -c -, generated configs can be piped in without a temp file
+fn read_stdin_config(mut stdin: impl std::io::Read) -> Result<Config, String> {
+    let mut conf_contents = String::new();
+    stdin
+        .read_to_string(&mut conf_contents)
+        .map_err(|e| format!("Can't read config from stdin: {}", e))?;
+    if conf_contents.trim().is_empty() {
+        return Err("No config on stdin, pipe one in or pass a file to -c".to_string());
+    }
+    parse_config(&conf_contents, "config from stdin")
 }
 
-fn send_key_up(virt_dev: &mut VirtualDevice, code: u16) {
-    send_key(virt_dev, code, KeyState::UP);
+// Errors point at the line they're on, e.g. a value missing its quotes
+fn parse_config(conf_contents: &str, source: &str) -> Result<Config, String> {
+    toml::from_str::<Config>(conf_contents).map_err(|e| {
+        let mut message = format!("Error parsing {}: {}", source, e);
+        if let Some((line, col)) = e.line_col() {
+            if let Some(content) = conf_contents.lines().nth(line) {
+                let number = (line + 1).to_string();
+                let margin = " ".repeat(number.len());
+                message += &format!("\n{} | {}\n{} | {}^", number, content, margin, " ".repeat(col));
+            }
+        }
+        message
+    })
 }
 
-fn send_key(virt_dev: &mut VirtualDevice, code: u16, value: KeyState) {
-    let event = InputEvent::new(evdev::EventType::KEY, code, value as i32);
-    virt_dev.emit(&[event]).unwrap();
+// What a mapped key sends
+enum Output {
+    // Pressed in order and released in reverse
+    Chord(Vec<u16>),
+    // A step on a relative axis, repeated every scroll_interval_ms while held
+    Rel(RelativeAxisType, i32),
+    // Chords tapped one after the other when the source key goes down
+    Text(Vec<Vec<u16>>),
+    // Moves the pointer along an axis in a direction, faster the longer it's held
+    Pointer(RelativeAxisType, i32),
+    // Toggles a modifier that's added to every chord mapped afterwards, until the layer is released
+    Sticky(u16),
+    // Turns off all sticky modifiers
+    ClearSticky,
+    // Toggles shifting letters typed outside the layer until one of caps_word_end
+    CapsWord,
+    // Swallows the key, for "none" or "KEY_RESERVED"
+    Nothing,
 }
 
-fn send_key_i32(virt_dev: &mut VirtualDevice, code: u16, value: i32) {
-    let event = InputEvent::new(evdev::EventType::KEY, code, value);
-    virt_dev.emit(&[event]).unwrap();
+// What a source key turns into while the fn layer is active
+struct Mapping {
+    output: Output,
+    // Used instead of output while a real modifier is held, the first one held wins
+    conditions: Vec<(Condition, Output)>,
+    // Overrides mode_switch_timeout when this key is the first one pressed in DECIDE
+    timeout: Option<Duration>,
 }
 
-struct StateMachine {
-    state: State,
-    virt_dev: VirtualDevice,
-    fn_key: Key,
-    pause_key: Key,
-    keymap: HashMap<u16, u16>,
-    timeout: Duration,
-    start_time: Instant,
-    event_buffer: Vec<u16>,
-    paused: bool,
-    #[cfg(feature = "tokey_ipc")]
-    messenger: tokey_ipc::Messenger
+impl Mapping {
+    // Index of the condition that applies with these modifiers held, if any
+    fn condition(&self, held_modifiers: &[u16]) -> Option<usize> {
+        self.conditions.iter().position(|(condition, _)| condition.held(held_modifiers))
+    }
+
+    fn output(&self, condition: Option<usize>) -> &Output {
+        condition.map_or(&self.output, |i| &self.conditions[i].1)
+    }
+
+    // Index of the output for tapping the key twice, if it has one
+    fn double_tap(&self) -> Option<usize> {
+        self.conditions.iter().position(|(condition, _)| *condition == Condition::DoubleTap)
+    }
+
+    fn outputs(&self) -> impl Iterator<Item = &Output> {
+        std::iter::once(&self.output).chain(self.conditions.iter().map(|(_, output)| output))
+    }
 }
 
-impl StateMachine {
-    fn new(
-        virt_dev: VirtualDevice,
-        config: Config,
-        #[cfg(feature = "tokey_ipc")]
-        messenger: tokey_ipc::Messenger
-    ) -> Self {
-        let fn_key = Key::from_str(config.fn_key.as_str().unwrap()).expect("Invalid fn_key");
-        let pause_key = Key::from_str(config.pause_key.as_str().unwrap()).expect("Invalid pause_key");
-        let keymap = get_keymap(config.keymap);
-        let mode_switch_timeout = config
-            .mode_switch_timeout
-            .as_integer()
-            .expect("Invalid mode_switch_timeout") as u64;
-        let timeout: Duration = Duration::from_millis(mode_switch_timeout);
-        let start_time = Instant::now();
-        let event_buffer = vec![0; 10];
-        
-        StateMachine {
-            state: State::IDLE,
-            virt_dev,
-            fn_key,
-            pause_key,
-            keymap,
-            timeout,
-            start_time,
-            event_buffer,
-            paused: false,
-            #[cfg(feature = "tokey_ipc")]
-            messenger}
+// A real modifier that changes what a key maps to, { default = ..., with_ctrl = ... },
+// or tapping the key twice, { tap = ..., double_tap = ... }
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Condition {
+    Ctrl,
+    Shift,
+    DoubleTap,
+}
+
+impl Condition {
+    // Checked in this order
+    const ALL: [(Condition, &'static str); 3] = [
+        (Condition::Ctrl, "with_ctrl"),
+        (Condition::Shift, "with_shift"),
+        (Condition::DoubleTap, "double_tap"),
+    ];
+
+    fn held(self, held_modifiers: &[u16]) -> bool {
+        let (left, right) = match self {
+            Condition::Ctrl => (Key::KEY_LEFTCTRL, Key::KEY_RIGHTCTRL),
+            Condition::Shift => (Key::KEY_LEFTSHIFT, Key::KEY_RIGHTSHIFT),
+            // Picked by the state machine, not by modifiers
+            Condition::DoubleTap => return false,
+        };
+        held_modifiers.iter().any(|code| *code == left.code() || *code == right.code())
     }
-    
-    fn run(&mut self, ev: InputEvent) -> bool {
-        match self.state {
-            State::IDLE => {self.state_idle(ev)}
-            State::DECIDE => {self.state_decide(ev)}
-            State::SHIFT => {self.state_shift(ev)}
-        }
+}
+
+// Names from [aliases] that stand in for a key anywhere in a keymap
+type Aliases = HashMap<String, Key>;
+
+fn get_aliases(table: toml::value::Table) -> Result<Aliases, String> {
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            if Key::from_str(&name).is_ok() {
+                return Err(format!("Alias {} shadows the key of the same name", name));
+            }
+            let key = get_key(&value, &format!("alias {}", name))?;
+            Ok((name, key))
+        })
+        .collect()
+}
+
+// A key name or an alias for one
+fn find_key(name: &str, aliases: &Aliases) -> Result<Key, String> {
+    if let Some(key) = parse_keycode(name) {
+        return key;
     }
-    
-    fn state_idle(&mut self, ev: InputEvent) -> bool {
-        let ev_kind = ev.kind();
-        let ev_code = ev.code();
-        let ev_value = ev.value();
-        if ev_kind == InputEventKind::Key(self.pause_key) && ev_value == KeyState::DOWN as i32 {
-            self.toggle_paused();
-            return true;
-        } else if ev_kind == InputEventKind::Key(self.fn_key) && !self.paused {
-            self.start_time = Instant::now();
-            self.state = State::DECIDE;
-            return true;
+    aliases
+        .get(name)
+        .copied()
+        .or_else(|| Key::from_str(name).ok())
+        .ok_or_else(|| {
+            if name.starts_with("KEY_") || name.starts_with("BTN_") {
+                format!("unknown key \"{}\"", name)
+            } else {
+                format!("undefined alias \"{}\"", name)
+            }
+        })
+}
+
+// "code:30" or "30", for keys evdev has no KEY_* name for. None if name isn't a keycode
+fn parse_keycode(name: &str) -> Option<Result<Key, String>> {
+    let (digits, prefixed) = match name.strip_prefix("code:") {
+        Some(digits) => (digits.trim(), true),
+        None => (name, false),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return prefixed.then(|| Err(format!("invalid keycode \"{}\"", name)));
+    }
+    // Too many digits for an i64 is out of range all the same
+    Some(keycode(digits.parse().unwrap_or(i64::MAX)))
+}
+
+fn keycode(code: i64) -> Result<Key, String> {
+    if (1..=MAX_KEYCODE).contains(&code) {
+        Ok(Key::new(code as u16))
+    } else {
+        Err(format!("keycode {} is out of range 1-{}", code, MAX_KEYCODE))
+    }
+}
+
+// Parses the whole keymap, listing every bad entry instead of stopping at the first
+fn get_keymap(
+    in_keymap: toml::value::Map<String, toml::Value>,
+    layout: &layout::Layout,
+    aliases: &Aliases,
+) -> Result<HashMap<u16, Mapping>, String> {
+    let mut keymap: HashMap<u16, Mapping> = HashMap::new();
+    let mut errors = Vec::new();
+    for (name, value) in in_keymap.iter() {
+        let k = find_key(name, aliases);
+        match (k, get_mapping(value, layout, aliases)) {
+            (Ok(k), Ok(v)) => {
+                keymap.insert(k.code(), v);
+            }
+            (k, v) => {
+                let problems: Vec<String> = k.err().into_iter().chain(v.err()).collect();
+                errors.push(format!("{} = {}: {}", name, value, problems.join(", ")));
+            }
         }
-        
-        send_key_i32(&mut self.virt_dev, ev_code, ev_value);
-        false
     }
-    
-    fn state_decide(&mut self, ev: InputEvent) -> bool {
-        let current_time = Instant::now();
-        if current_time.duration_since(self.start_time) >= self.timeout {
-            // Send all buffered key events as down then up
-            for i in &self.event_buffer {
-                let mut code = *i;
-                if self.keymap.contains_key(&code) {
-                    code = self.keymap[&code];
-                }
-                send_key_down(&mut self.virt_dev, code);
-                send_key_up(&mut self.virt_dev, code);
+
+    if errors.is_empty() {
+        Ok(keymap)
+    } else {
+        Err(format!("Invalid keymap entries:\n  {}", errors.join("\n  ")))
+    }
+}
+
+// Plain key to key swaps from [global_keymap], listing every bad entry like get_keymap
+fn get_global_keymap(in_keymap: toml::value::Table, aliases: &Aliases) -> Result<HashMap<u16, u16>, String> {
+    let mut keymap = HashMap::new();
+    let mut errors = Vec::new();
+    for (name, value) in in_keymap.iter() {
+        let to = match value {
+            toml::Value::String(to) => find_key(to, aliases),
+            _ => Err("must be a single key".to_string()),
+        };
+        match (find_key(name, aliases), to) {
+            (Ok(k), Ok(to)) => {
+                keymap.insert(k.code(), to.code());
             }
-            self.event_buffer.clear();
-            self.state = State::SHIFT;
-            return true;
-        } else {
-            match ev.value().into() {
-                KeyState::DOWN => { 
-                    // add to event buffer
-                    self.event_buffer.push(ev.code());
-                }
-                KeyState::UP => {
-                    let mut code = ev.code();
-                    if ev.kind() == InputEventKind::Key(self.fn_key) {
-                        send_key_down(&mut self.virt_dev, code);
-                        send_key_up(&mut self.virt_dev, code);
-                        // Send all buffered key events as down
-                        for i in &self.event_buffer {
-                            send_key_down(&mut self.virt_dev, *i);
-                        }
-                        self.event_buffer.clear();
-                        self.state = State::IDLE;
-                        return true;
-                    } else if self.event_buffer.contains(&code) {
-                        // remove ev from buffer
-                        self.event_buffer.retain(|c| c != &code);
-                        if self.keymap.contains_key(&code) {
-                            code = self.keymap[&code];
-                        }
-                        
-                        send_key_down(&mut self.virt_dev, code);
-                        send_key_up(&mut self.virt_dev, code);
-                        self.state = State::SHIFT;
-                        return true;
-                    } else {
-                        // key was pressed before fn_key
-                        send_key_i32(&mut self.virt_dev, ev.code(), ev.value());
-                    }
-                }
-                _ => {}
+            (k, to) => {
+                let problems: Vec<String> = k.err().into_iter().chain(to.err()).collect();
+                errors.push(format!("{} = {}: {}", name, value, problems.join(", ")));
             }
         }
-        
-        false
     }
-    
-    fn state_shift(&mut self, ev: InputEvent) -> bool {
-        if ev.kind() == InputEventKind::Key(self.fn_key) {
-            if ev.value() == KeyState::UP as i32 {
-                // Send all buffered key events as up
-                for i in &self.event_buffer {
-                    send_key_up(&mut self.virt_dev, *i);
-                }
-                self.event_buffer.clear();
-                self.state = State::IDLE;
-                return true;
+    if errors.is_empty() {
+        Ok(keymap)
+    } else {
+        Err(format!("Invalid global_keymap entries:\n  {}", errors.join("\n  ")))
+    }
+}
+
+// A mapping is either a bare chord, { to = chord, timeout = ms }, { text = "string", timeout = ms }
+// or { sticky = modifier } / { sticky = "clear" }. "default" is another name for "to",
+// read better next to with_ctrl and with_shift
+fn get_mapping(value: &toml::Value, layout: &layout::Layout, aliases: &Aliases) -> Result<Mapping, String> {
+    match value {
+        toml::Value::Table(t) => {
+            // Different names for the same thing, whichever reads best next to the conditions
+            let mut to = ["to", "default", "tap"].iter().filter_map(|name| t.get(*name));
+            let (to, other) = (to.next(), to.next());
+            if other.is_some() {
+                return Err("only one of \"to\", \"default\" and \"tap\" is allowed".to_string());
             }
+            let output = match (to, t.get("text"), t.get("sticky")) {
+                (Some(to), None, None) => get_output(to, aliases)?,
+                (None, Some(text), None) => get_text(text, layout)?,
+                (None, None, Some(sticky)) => get_sticky(sticky, aliases)?,
+                (None, None, None) => return Err("missing \"to\"".to_string()),
+                _ => return Err("only one of \"to\", \"text\" and \"sticky\" is allowed".to_string()),
+            };
+            let timeout = match t.get("timeout") {
+                Some(ms) => Some(Duration::from_millis(
+                    ms.as_integer()
+                        .filter(|ms| *ms >= 0)
+                        .ok_or_else(|| format!("invalid timeout {}", ms))? as u64,
+                )),
+                None => None,
+            };
+            let conditions = Condition::ALL
+                .iter()
+                .filter_map(|(condition, name)| {
+                    t.get(*name).map(|value| {
+                        get_output(value, aliases)
+                            .map(|output| (*condition, output))
+                            .map_err(|e| format!("{}: {}", name, e))
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Mapping { output, conditions, timeout })
         }
+        _ => Ok(Mapping {
+            output: get_output(value, aliases)?,
+            conditions: Vec::new(),
+            timeout: None,
+        }),
+    }
+}
 
-        if self.keymap.contains_key(&ev.code()) {
-            let mapped_code = self.keymap[&ev.code()];
-            
-            match ev.value().into() {
-                KeyState::UP => {
-                    // remove ev from buffer
-                    self.event_buffer.retain(|c| c != &mapped_code);
-                }
-                KeyState::DOWN => {
-                    self.event_buffer.push(mapped_code);
+fn get_output(value: &toml::Value, aliases: &Aliases) -> Result<Output, String> {
+    if matches!(value.as_str(), Some("none" | "KEY_RESERVED")) {
+        return Ok(Output::Nothing);
+    }
+    match value.as_str().and_then(|s| s.split_once(':')) {
+        // "code:30" is a keycode, not an axis
+        Some((axis, step)) if axis.trim().starts_with("REL_") => get_rel(axis, step),
+        Some(("mouse", direction)) => get_pointer(direction),
+        _ => Ok(Output::Chord(get_chord(value, aliases)?)),
+    }
+}
+
+// Each character becomes the chord that types it on the configured layout
+fn get_text(value: &toml::Value, layout: &layout::Layout) -> Result<Output, String> {
+    let text = value
+        .as_str()
+        .filter(|text| !text.is_empty())
+        .ok_or_else(|| format!("invalid text {}", value))?;
+    text.chars()
+        .map(|c| {
+            layout
+                .chord_for(c)
+                .ok_or_else(|| format!("can't type {:?} on the {} layout", c, layout.name))
+        })
+        .collect::<Result<_, _>>()
+        .map(Output::Text)
+}
+
+fn get_sticky(value: &toml::Value, aliases: &Aliases) -> Result<Output, String> {
+    let name = value.as_str().ok_or_else(|| format!("invalid sticky {}", value))?;
+    match name {
+        "clear" => return Ok(Output::ClearSticky),
+        "caps_word" => return Ok(Output::CapsWord),
+        _ => {}
+    }
+    let key = find_key(name, aliases)?;
+    if !is_modifier(key.code()) {
+        return Err(format!("sticky key {:?} is not a modifier", key));
+    }
+    Ok(Output::Sticky(key.code()))
+}
+
+// "REL_WHEEL:+1", the axis and the step sent on press and on every repeat
+fn get_rel(axis: &str, step: &str) -> Result<Output, String> {
+    let axis = RelativeAxisType::from_str(axis.trim())
+        .map_err(|_| format!("unknown axis \"{}\"", axis))?;
+    let step = step
+        .trim()
+        .parse::<i32>()
+        .map_err(|_| format!("invalid step \"{}\"", step))?;
+    Ok(Output::Rel(axis, step))
+}
+
+// "mouse:left", a pointer direction
+fn get_pointer(direction: &str) -> Result<Output, String> {
+    match direction.trim() {
+        "left" => Ok(Output::Pointer(RelativeAxisType::REL_X, -1)),
+        "right" => Ok(Output::Pointer(RelativeAxisType::REL_X, 1)),
+        "up" => Ok(Output::Pointer(RelativeAxisType::REL_Y, -1)),
+        "down" => Ok(Output::Pointer(RelativeAxisType::REL_Y, 1)),
+        _ => Err(format!("unknown pointer direction \"{}\"", direction)),
+    }
+}
+
+// A chord is either "KEY_A+KEY_B" or ["KEY_A", "KEY_B"], modifiers first.
+// Keycodes can be given as integers too
+fn get_chord(value: &toml::Value, aliases: &Aliases) -> Result<Vec<u16>, String> {
+    let names: Vec<String> = match value {
+        toml::Value::String(s) => s.split('+').map(|name| name.trim().to_string()).collect(),
+        toml::Value::Integer(code) => vec![code.to_string()],
+        toml::Value::Array(a) => a
+            .iter()
+            .map(|v| match v {
+                toml::Value::String(name) => Ok(name.clone()),
+                toml::Value::Integer(code) => Ok(code.to_string()),
+                _ => Err(format!("{} is not a string", v)),
+            })
+            .collect::<Result<_, _>>()?,
+        other => return Err(format!("{} is not a string or array", other)),
+    };
+    if names.is_empty() {
+        return Err("empty chord".to_string());
+    }
+
+    let (keys, unknown): (Vec<_>, Vec<_>) = names
+        .iter()
+        .map(|name| find_key(name, aliases))
+        .partition(Result::is_ok);
+    if !unknown.is_empty() {
+        return Err(unknown.into_iter().map(Result::unwrap_err).collect::<Vec<_>>().join(", "));
+    }
+
+    Ok(keys.into_iter().map(|key| key.unwrap().code()).collect())
+}
+
+// "KEY_G KEY_G" = "KEY_LEFTCTRL+KEY_HOME", keys typed one after the other and the chord they send
+fn get_sequences(table: toml::value::Table, aliases: &Aliases) -> Result<HashMap<Vec<u16>, Vec<u16>>, String> {
+    let sequences: HashMap<Vec<u16>, Vec<u16>> = table
+        .iter()
+        .map(|(keys, value)| {
+            let sequence = keys
+                .split_whitespace()
+                .map(|name| find_key(name, aliases).map(|key| key.code()))
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|sequence| {
+                    if sequence.is_empty() {
+                        Err("empty sequence".to_string())
+                    } else {
+                        Ok(sequence)
+                    }
+                });
+            match (sequence, get_chord(value, aliases)) {
+                (Ok(sequence), Ok(chord)) => Ok((sequence, chord)),
+                (sequence, chord) => {
+                    let problems: Vec<String> = sequence.err().into_iter().chain(chord.err()).collect();
+                    Err(format!("Invalid sequence {} = {}: {}", keys, value, problems.join(", ")))
                 }
-                _ => {}
             }
-
-            send_key_i32(&mut self.virt_dev, mapped_code, ev.value());
-        } else {
-            send_key_i32(&mut self.virt_dev, ev.code(), ev.value());
+        })
+        .collect::<Result<_, _>>()?;
+    // A sequence fires as soon as it's complete, so a longer one starting with it could never be typed
+    for (sequence, _) in sequences.iter() {
+        if let Some((longer, _)) = sequences
+            .iter()
+            .find(|(other, _)| other.len() > sequence.len() && other.starts_with(sequence))
+        {
+            let names = |codes: &[u16]| {
+                codes.iter().map(|c| format!("{:?}", Key::new(*c))).collect::<Vec<_>>().join(" ")
+            };
+            return Err(format!(
+                "Sequence \"{}\" is the start of \"{}\", which can never be typed",
+                names(sequence),
+                names(longer)
+            ));
         }
-        
-        false
     }
-    
-    fn toggle_paused(&mut self) {
-        self.paused = !self.paused;
-        #[cfg(feature = "tokey_ipc")]
-        self.messenger.set_paused(self.paused);
+    Ok(sequences)
+}
+
+fn get_layout(name: Option<&str>) -> &'static layout::Layout {
+    match name {
+        None => &layout::US,
+        Some(name) => layout::find(name).unwrap_or_else(|| {
+            warn!(
+                "Unknown layout {}, using us. Known layouts: {}",
+                name,
+                layout::names().join(", ")
+            );
+            &layout::US
+        }),
     }
 }
 
+// Whole ms, a float is cut off and a timeout that's too short is raised with a warning
+fn get_mode_switch_timeout(value: Option<&toml::Value>) -> Result<Duration, String> {
+    let ms = match value {
+        None => DEFAULT_MODE_SWITCH_TIMEOUT_MS,
+        Some(toml::Value::Integer(ms)) if *ms >= 0 => *ms as u64,
+        Some(toml::Value::Float(ms)) if *ms >= 0.0 => ms.trunc() as u64,
+        Some(other) => return Err(format!("Invalid mode_switch_timeout: {}", other)),
+    };
+    if ms < MIN_MODE_SWITCH_TIMEOUT_MS {
+        warn!(
+            "mode_switch_timeout {} is too short to tell taps from holds, using {}",
+            ms, MIN_MODE_SWITCH_TIMEOUT_MS
+        );
+        return Ok(Duration::from_millis(MIN_MODE_SWITCH_TIMEOUT_MS));
+    }
+    Ok(Duration::from_millis(ms))
+}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // setup
-    let config = get_config();
-    let mut dev = get_device(config.device_name.to_string()).expect("Invalid input device");
-    let virt_dev = evdev::uinput::VirtualDeviceBuilder::new()?
-        .name("tokey-kbd")
-        .with_keys(dev.supported_keys().unwrap())?
-        .build()
-        .unwrap();
-    
-    let mut state_machine = StateMachine::new(
-        virt_dev,
-        config,
-        #[cfg(feature = "tokey_ipc")]
-        tokey_ipc::Messenger::new()
-    );
-    
-    // Sleep for 100ms to avoid capturing the keypress used to start the program
-    std::thread::sleep(Duration::from_millis(100));
-    
-    let _ = dev.grab();
-    loop {
-        match dev.fetch_events() {
-            Ok(iterator) => {
-                for ev in iterator {
-                    if ev.code() == 0 || ev.event_type() != evdev::EventType::KEY {
-                        continue;
-                    }
-                    
-                    if state_machine.run(ev) {
-                        break;
+// [min, max] in ms, the timeout follows typing speed within them
+fn get_adaptive_timeout(value: Option<&toml::Value>) -> Result<Option<(Duration, Duration)>, String> {
+    let Some(value) = value else { return Ok(None) };
+    let bounds: Option<Vec<u64>> = value.as_array().and_then(|bounds| {
+        bounds.iter().map(|ms| ms.as_integer().and_then(|ms| u64::try_from(ms).ok())).collect()
+    });
+    match bounds.as_deref() {
+        Some(&[min, max]) if min <= max => Ok(Some((
+            Duration::from_millis(min.max(MIN_MODE_SWITCH_TIMEOUT_MS)),
+            Duration::from_millis(max.max(MIN_MODE_SWITCH_TIMEOUT_MS)),
+        ))),
+        _ => Err(format!("Invalid adaptive_timeout_ms: {}, use [min, max] in ms", value)),
+    }
+}
+
+// BTN_* codes share the KEY event type but keyboards don't declare them
+fn is_button(code: u16) -> bool {
+    (Key::BTN_0.code()..Key::KEY_OK.code()).contains(&code)
+        || (Key::BTN_TRIGGER_HAPPY1.code()..=Key::BTN_TRIGGER_HAPPY40.code()).contains(&code)
+}
+
+fn get_key(value: &toml::Value, field: &str) -> Result<Key, String> {
+    let key = match value {
+        toml::Value::Integer(code) => keycode(*code).ok(),
+        toml::Value::String(name) => match parse_keycode(name) {
+            Some(key) => key.ok(),
+            None => Key::from_str(name).ok(),
+        },
+        _ => None,
+    };
+    key.ok_or_else(|| format!("Invalid {}: {}", field, value))
+}
+
+#[derive(Debug)]
+enum DeviceError {
+    Open(String, std::io::Error),
+    NotFound { name: String, available: Vec<String> },
+    InvalidName(String),
+    // Creating the virtual device failed, usually a setup problem with /dev/uinput
+    Uinput(std::io::Error),
+    // Grabbing a device kept failing, the name is the device's
+    Grab(String, std::io::Error),
+    Io(std::io::Error),
+}
+
+// How to get write access to /dev/uinput, given with the uinput error and by the check up front
+const UINPUT_ACCESS_ADVICE: &str = "Add yourself to the input group with `sudo usermod -aG input $USER`\n\
+     and log in again. If /dev/uinput still belongs to root, give the group access with a udev rule in\n\
+     /etc/udev/rules.d/99-tokey.rules like\n  \
+     KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\", OPTIONS+=\"static_node=uinput\"\n\
+     and reload with `sudo udevadm control --reload && sudo udevadm trigger`";
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::Open(path, err) => write!(f, "Can't open input device {}: {}", path, err),
+            DeviceError::NotFound { name, available } => {
+                writeln!(f, "No input device with a name containing \"{}\"", name)?;
+                if available.is_empty() {
+                    write!(f, "No readable input devices were found")
+                } else {
+                    write!(f, "Available devices:")?;
+                    for dev_name in available {
+                        write!(f, "\n  {}", dev_name)?;
                     }
+                    Ok(())
                 }
             }
-            Err(e) => {
-                eprintln!("{}", e);
-                break;
-            }
+            DeviceError::InvalidName(value) => write!(
+                f,
+                "device_name must be a string or an array of strings, got {}",
+                value
+            ),
+            DeviceError::Uinput(err) if err.kind() == std::io::ErrorKind::NotFound
+                || err.raw_os_error() == Some(nix::libc::ENODEV) => write!(
+                f,
+                "Can't create the virtual keyboard, /dev/uinput is missing: {}\n\
+                 Load the uinput kernel module with `sudo modprobe uinput`,\n\
+                 and to load it on every boot: `echo uinput | sudo tee /etc/modules-load.d/uinput.conf`",
+                err
+            ),
+            DeviceError::Uinput(err) if err.kind() == std::io::ErrorKind::PermissionDenied => write!(
+                f,
+                "Can't create the virtual keyboard, no write access to /dev/uinput: {}\n{}",
+                err, UINPUT_ACCESS_ADVICE
+            ),
+            DeviceError::Uinput(err) => write!(f, "Can't create the virtual keyboard: {}", err),
+            DeviceError::Grab(name, err) if err.raw_os_error() == Some(nix::libc::EBUSY) => write!(
+                f,
+                "Can't grab {}, another program already grabbed it: {}\n\
+                 Check for another tokey instance or remapper (keyd, kmonad, interception tools)",
+                name, err
+            ),
+            DeviceError::Grab(name, err) if err.kind() == std::io::ErrorKind::PermissionDenied => write!(
+                f,
+                "Can't grab {}, not allowed to: {}\n\
+                 Run tokey as root or add yourself to the input group with `sudo usermod -aG input $USER`",
+                name, err
+            ),
+            DeviceError::Grab(name, err) => write!(f, "Can't grab {}: {}", name, err),
+            DeviceError::Io(err) => write!(f, "Can't set up input device: {}", err),
         }
     }
+}
 
-    dev.ungrab()?;
-    Ok(())
-}
\ No newline at end of file
+impl std::error::Error for DeviceError {}
+
+impl From<std::io::Error> for DeviceError {
+    fn from(err: std::io::Error) -> Self {
+        DeviceError::Io(err)
+    }
+}
+
+impl From<nix::Error> for DeviceError {
+    fn from(err: nix::Error) -> Self {
+        DeviceError::Io(err.into())
+    }
+}
+
+fn get_devices(device_names: &toml::Value) -> Result<Vec<evdev::Device>, DeviceError> {
+    let names: Vec<&str> = match device_names {
+        toml::Value::String(name) => vec![name.as_str()],
+        toml::Value::Array(names) => names
+            .iter()
+            .map(|n| n.as_str().ok_or_else(|| DeviceError::InvalidName(n.to_string())))
+            .collect::<Result<_, _>>()?,
+        other => return Err(DeviceError::InvalidName(other.to_string())),
+    };
+
+    names.into_iter().map(get_device).collect()
+}
+
+fn get_device(device_name: &str) -> Result<evdev::Device, DeviceError> {
+    let device = if device_name.starts_with("/dev/input/") {
+        evdev::Device::open(device_name).map_err(|e| DeviceError::Open(device_name.to_string(), e))?
+    } else {
+        let mut available = Vec::new();
+        // Devices without a readable name are skipped rather than matched
+        let found = evdev::enumerate().find(|d| match d.name() {
+            Some(name) => {
+                available.push(name.to_string());
+                name.contains(device_name)
+            }
+            None => false,
+        });
+        match found {
+            Some(d) => d,
+            None => {
+                return Err(DeviceError::NotFound {
+                    name: device_name.to_string(),
+                    available,
+                })
+            }
+        }
+    };
+
+    nix::fcntl::fcntl(device.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+    Ok(device)
+}
+
+// Most setups that don't work lack one of these, so they're checked up front and explained
+// together. `devices` are the configured device paths, or every event device when matching
+// by name, in which case one readable device is enough. Only opens files, nothing is grabbed
+fn permission_problems(devices: &[PathBuf], by_name: bool, uinput: Option<&Path>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let unreadable: Vec<(&PathBuf, std::io::Error)> = devices
+        .iter()
+        .filter_map(|path| std::fs::File::open(path).err().map(|e| (path, e)))
+        .collect();
+    if by_name && !devices.is_empty() && unreadable.len() == devices.len() {
+        problems.push(format!("none of the {} input devices in /dev/input can be read", devices.len()));
+    } else if !by_name {
+        for (path, e) in unreadable {
+            problems.push(format!("can't read {}: {}", path.display(), e));
+        }
+    }
+    if let Some(uinput) = uinput {
+        match std::fs::OpenOptions::new().write(true).open(uinput) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => problems.push(format!(
+                "{} is missing, load the uinput module with `sudo modprobe uinput`",
+                uinput.display()
+            )),
+            Err(e) => problems.push(format!("can't write to {}: {}", uinput.display(), e)),
+        }
+    }
+    problems
+}
+
+// The paths in device_name, None if it names any device by name
+fn configured_device_paths(device_name: &toml::Value) -> Option<Vec<PathBuf>> {
+    let names = match device_name {
+        toml::Value::Array(names) => names.iter().collect(),
+        name => vec![name],
+    };
+    names
+        .into_iter()
+        .map(|name| name.as_str().filter(|name| name.starts_with("/dev/input/")).map(PathBuf::from))
+        .collect()
+}
+
+fn permission_advice(problems: &[String]) -> String {
+    format!("tokey is missing permissions it needs:\n  {}\n{}", problems.join("\n  "), UINPUT_ACCESS_ADVICE)
+}
+
+// epoll tags of the command channel and the virtual device, devices are tagged with their index
+const COMMANDS_EPOLL_TAG: u64 = u64::MAX;
+const FEEDBACK_EPOLL_TAG: u64 = u64::MAX - 1;
+
+fn epoll_add(epoll_fd: RawFd, fd: RawFd, tag: u64) -> nix::Result<()> {
+    let mut event = epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, tag);
+    epoll::epoll_ctl(epoll_fd, epoll::EpollOp::EpollCtlAdd, fd, Some(&mut event))
+}
+
+// Create an epoll handle watching every device, tagged with its index in `devices`
+fn create_epoll(devices: &[evdev::Device]) -> nix::Result<RawFd> {
+    let epoll_fd = epoll::epoll_create1(epoll::EpollCreateFlags::EPOLL_CLOEXEC)?;
+    for (i, device) in devices.iter().enumerate() {
+        epoll_add(epoll_fd, device.as_raw_fd(), i as u64)?;
+    }
+
+    Ok(epoll_fd)
+}
+
+// Output side of tokey, implemented by the uinput device and by test fakes
+trait KeyEmitter {
+    fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()>;
+    
+    // Several key events at once, emitters that can should write them in one go
+    fn emit_keys(&mut self, keys: &[(u16, i32)]) -> std::io::Result<()> {
+        for (code, value) in keys {
+            self.emit(*code, *value)?;
+        }
+        Ok(())
+    }
+    
+    // Anything that isn't a key, e.g. REL_WHEEL for scrolling
+    fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()>;
+    
+    // Keys mixed with other events, emitters that can should write them in one go
+    fn emit_events(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        for event in events {
+            if event.event_type() == evdev::EventType::KEY {
+                self.emit(event.code(), event.value())?;
+            } else {
+                self.emit_event(*event)?;
+            }
+        }
+        Ok(())
+    }
+    
+    // The MSC_SCAN a keyboard sent along with code, only kept by emitters that pass scancodes on
+    fn learn_scancode(&mut self, _code: u16, _scancode: i32) {}
+    
+    // Delimit a logical group of emitted events with a SYN_REPORT
+    fn syn(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+    
+    // Keys this emitted a press for and no release yet, only known to emitters that track them
+    fn pressed(&self) -> Vec<u16> {
+        Vec::new()
+    }
+    
+    // Everything emitted since the last report was caused by source, or by a timer when it's None
+    fn report(&mut self, _source: Option<InputEvent>, _state: &str) {}
+}
+
+impl KeyEmitter for VirtualDevice {
+    fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+        debug!("emit {:?} {}", Key::new(code), value);
+        let event = InputEvent::new(evdev::EventType::KEY, code, value);
+        VirtualDevice::emit(self, &[event])
+    }
+    
+    // One write, so the events also share a SYN_REPORT
+    fn emit_keys(&mut self, keys: &[(u16, i32)]) -> std::io::Result<()> {
+        let events: Vec<InputEvent> = keys
+            .iter()
+            .map(|(code, value)| {
+                debug!("emit {:?} {}", Key::new(*code), value);
+                InputEvent::new(evdev::EventType::KEY, *code, *value)
+            })
+            .collect();
+        VirtualDevice::emit(self, &events)
+    }
+    
+    fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+        debug!("emit {:?}", event);
+        VirtualDevice::emit(self, &[event])
+    }
+    
+    fn emit_events(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        for event in events {
+            debug!("emit {:?}", event);
+        }
+        VirtualDevice::emit(self, events)
+    }
+    
+    fn syn(&mut self) -> std::io::Result<()> {
+        // VirtualDevice::emit always appends a SYN_REPORT to what it writes
+        VirtualDevice::emit(self, &[])
+    }
+}
+
+// Used by --monitor, reports what would be emitted without touching uinput
+struct MonitorEmitter;
+
+impl KeyEmitter for MonitorEmitter {
+    fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+        info!("would emit {:?} {}", Key::new(code), value);
+        Ok(())
+    }
+    
+    fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+        info!("would emit {:?}", event);
+        Ok(())
+    }
+    
+    fn syn(&mut self) -> std::io::Result<()> {
+        info!("would emit SYN_REPORT");
+        Ok(())
+    }
+}
+
+fn monitor_emitter(json: bool) -> Box<dyn KeyEmitter> {
+    match json {
+        true => Box::<JsonEmitter>::default(),
+        false => Box::new(MonitorEmitter),
+    }
+}
+
+// Used by --format json, prints one line of JSON per input event with what it turned into
+#[derive(Default)]
+struct JsonEmitter {
+    emitted: Vec<InputEvent>,
+}
+
+impl KeyEmitter for JsonEmitter {
+    fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+        self.emitted.push(InputEvent::new(evdev::EventType::KEY, code, value));
+        Ok(())
+    }
+    
+    fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+        self.emitted.push(event);
+        Ok(())
+    }
+    
+    fn report(&mut self, source: Option<InputEvent>, state: &str) {
+        // Timers that didn't do anything aren't worth a line
+        if source.is_some() || !self.emitted.is_empty() {
+            println!("{}", decision_json(source.as_ref(), &self.emitted, state));
+        }
+        self.emitted.clear();
+    }
+}
+
+// {"source": "KEY_J", "value": 1, "action": "mapped", "emitted": [...], "state": "SHIFT"}
+fn decision_json(source: Option<&InputEvent>, emitted: &[InputEvent], state: &str) -> String {
+    let event_json = |ev: &InputEvent| {
+        format!("{{\"code\":{},\"value\":{}}}", json_string(&event_name(ev)), ev.value())
+    };
+    let action = match source {
+        None => "timer",
+        Some(_) if emitted.is_empty() => "held",
+        Some(ev) if emitted.len() == 1 && same_event(&emitted[0], ev) => "passthrough",
+        Some(_) => "mapped",
+    };
+    format!(
+        "{{\"source\":{},\"value\":{},\"action\":\"{}\",\"emitted\":[{}],\"state\":\"{}\"}}",
+        source.map_or("null".to_string(), |ev| json_string(&event_name(ev))),
+        source.map_or("null".to_string(), |ev| ev.value().to_string()),
+        action,
+        emitted.iter().map(event_json).collect::<Vec<_>>().join(","),
+        state
+    )
+}
+
+fn same_event(a: &InputEvent, b: &InputEvent) -> bool {
+    (a.event_type(), a.code(), a.value()) == (b.event_type(), b.code(), b.value())
+}
+
+fn event_name(ev: &InputEvent) -> String {
+    match ev.kind() {
+        InputEventKind::Key(key) => format!("{:?}", key),
+        InputEventKind::RelAxis(axis) => format!("{:?}", axis),
+        InputEventKind::Misc(misc) => format!("{:?}", misc),
+        _ => format!("{:?}:{}", ev.event_type(), ev.code()),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Writes what --format json prints to log_file as well, with the time in front
+struct AuditEmitter {
+    inner: Box<dyn KeyEmitter>,
+    log: audit::AuditLog,
+    emitted: Vec<InputEvent>,
+}
+
+impl KeyEmitter for AuditEmitter {
+    fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+        self.inner.emit(code, value)?;
+        self.emitted.push(InputEvent::new(evdev::EventType::KEY, code, value));
+        Ok(())
+    }
+    
+    fn emit_keys(&mut self, keys: &[(u16, i32)]) -> std::io::Result<()> {
+        self.inner.emit_keys(keys)?;
+        let events = keys.iter().map(|(code, value)| InputEvent::new(evdev::EventType::KEY, *code, *value));
+        self.emitted.extend(events);
+        Ok(())
+    }
+    
+    fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+        self.inner.emit_event(event)?;
+        self.emitted.push(event);
+        Ok(())
+    }
+    
+    fn emit_events(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        self.inner.emit_events(events)?;
+        self.emitted.extend_from_slice(events);
+        Ok(())
+    }
+    
+    fn syn(&mut self) -> std::io::Result<()> {
+        self.inner.syn()
+    }
+    
+    fn learn_scancode(&mut self, code: u16, scancode: i32) {
+        self.inner.learn_scancode(code, scancode);
+    }
+    
+    fn report(&mut self, source: Option<InputEvent>, state: &str) {
+        if source.is_some() || !self.emitted.is_empty() {
+            let decision = decision_json(source.as_ref(), &self.emitted, state);
+            let now = std::time::UNIX_EPOCH.elapsed().unwrap_or_default();
+            let time = format!("{}.{:03}", now.as_secs(), now.subsec_millis());
+            let line = format!("{{\"time\":{},{}", time, &decision[1..]);
+            if let Err(e) = self.log.write_line(&line) {
+                warn!("Can't write to log_file: {}", e);
+            }
+        }
+        self.emitted.clear();
+        self.inner.report(source, state);
+    }
+}
+
+fn open_audit_log(path: &Path) -> Option<audit::AuditLog> {
+    let path = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => xdg::BaseDirectories::with_prefix("tokey")
+            .map_err(|e| e.to_string())
+            .and_then(|dirs| dirs.place_state_file(path).map_err(|e| e.to_string()))
+            .map_err(|e| warn!("Can't create state directory for log_file: {}", e))
+            .ok()?,
+    };
+    audit::AuditLog::open(&path, LOG_FILE_MAX_BYTES)
+        .map_err(|e| warn!("Can't open log_file {}: {}", path.display(), e))
+        .ok()
+}
+
+// Puts a MSC_SCAN in front of every key that has a known scancode, for apps that read them.
+// Scancodes are learned from the keyboard, so a mapped key gets its target's once that was pressed
+struct ScancodeEmitter {
+    inner: Box<dyn KeyEmitter>,
+    scancodes: HashMap<u16, i32>,
+}
+
+impl KeyEmitter for ScancodeEmitter {
+    fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+        self.emit_keys(&[(code, value)])
+    }
+    
+    fn emit_keys(&mut self, keys: &[(u16, i32)]) -> std::io::Result<()> {
+        let mut events = Vec::with_capacity(keys.len() * 2);
+        for (code, value) in keys {
+            if let Some(scancode) = self.scancodes.get(code) {
+                events.push(InputEvent::new(evdev::EventType::MISC, evdev::MiscType::MSC_SCAN.0, *scancode));
+            }
+            events.push(InputEvent::new(evdev::EventType::KEY, *code, *value));
+        }
+        self.inner.emit_events(&events)
+    }
+    
+    fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+        // Keys with their own timestamp come through here
+        match self.scancodes.get(&event.code()).filter(|_| event.event_type() == evdev::EventType::KEY) {
+            Some(scancode) => {
+                let scan = InputEvent::new(evdev::EventType::MISC, evdev::MiscType::MSC_SCAN.0, *scancode);
+                self.inner.emit_events(&[scan, event])
+            }
+            None => self.inner.emit_event(event),
+        }
+    }
+    
+    fn emit_events(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        self.inner.emit_events(events)
+    }
+    
+    fn syn(&mut self) -> std::io::Result<()> {
+        self.inner.syn()
+    }
+    
+    fn learn_scancode(&mut self, code: u16, scancode: i32) {
+        self.scancodes.insert(code, scancode);
+    }
+    
+    fn report(&mut self, source: Option<InputEvent>, state: &str) {
+        self.inner.report(source, state);
+    }
+}
+
+// A failed write loses that event but shouldn't take the daemon down with it.
+// Writes interrupted by a signal are tried once more
+fn emit_or_log(what: &str, mut write: impl FnMut() -> std::io::Result<()>) {
+    let result = match write() {
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => write(),
+        result => result,
+    };
+    if let Err(e) = result {
+        error!("Couldn't emit {}: {}", what, e);
+    }
+}
+
+// Remembers which keys are down on the output, so they can all be let go at once
+struct PressedEmitter {
+    inner: Box<dyn KeyEmitter>,
+    pressed: Vec<u16>,
+}
+
+impl PressedEmitter {
+    fn track(&mut self, code: u16, value: i32) {
+        match KeyState::try_from(value) {
+            Ok(KeyState::DOWN) if !self.pressed.contains(&code) => self.pressed.push(code),
+            Ok(KeyState::UP) => self.pressed.retain(|c| *c != code),
+            _ => {}
+        }
+    }
+}
+
+impl KeyEmitter for PressedEmitter {
+    fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+        self.inner.emit(code, value)?;
+        self.track(code, value);
+        Ok(())
+    }
+    
+    fn emit_keys(&mut self, keys: &[(u16, i32)]) -> std::io::Result<()> {
+        self.inner.emit_keys(keys)?;
+        for (code, value) in keys {
+            self.track(*code, *value);
+        }
+        Ok(())
+    }
+    
+    fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+        self.inner.emit_event(event)?;
+        if event.event_type() == evdev::EventType::KEY {
+            self.track(event.code(), event.value());
+        }
+        Ok(())
+    }
+    
+    fn emit_events(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        self.inner.emit_events(events)?;
+        for event in events.iter().filter(|event| event.event_type() == evdev::EventType::KEY) {
+            self.track(event.code(), event.value());
+        }
+        Ok(())
+    }
+    
+    fn learn_scancode(&mut self, code: u16, scancode: i32) {
+        self.inner.learn_scancode(code, scancode);
+    }
+    
+    fn syn(&mut self) -> std::io::Result<()> {
+        self.inner.syn()
+    }
+    
+    fn pressed(&self) -> Vec<u16> {
+        self.pressed.clone()
+    }
+    
+    fn report(&mut self, source: Option<InputEvent>, state: &str) {
+        self.inner.report(source, state);
+    }
+}
+
+fn send_key_down(emitter: &mut dyn KeyEmitter, code: u16) {
+    send_key(emitter, code, KeyState::DOWN);
+}
+
+fn send_key_up(emitter: &mut dyn KeyEmitter, code: u16) {
+    send_key(emitter, code, KeyState::UP);
+}
+
+fn send_key(emitter: &mut dyn KeyEmitter, code: u16, value: KeyState) {
+    send_key_i32(emitter, code, value as i32);
+}
+
+fn send_key_i32(emitter: &mut dyn KeyEmitter, code: u16, value: i32) {
+    emit_or_log("key", || emitter.emit(code, value));
+}
+
+// All in one write, or one at a time with `delay` after each for compositors that drop fast events
+fn send_keys(emitter: &mut dyn KeyEmitter, keys: &[(u16, i32)], delay: Duration) {
+    if delay.is_zero() {
+        emit_or_log("keys", || emitter.emit_keys(keys));
+        return;
+    }
+    for (code, value) in keys {
+        emit_or_log("key", || emitter.emit_keys(&[(*code, *value)]));
+        std::thread::sleep(delay);
+    }
+}
+
+// A key event with the given time, InputEvent::new leaves it for the kernel to fill in
+fn stamped(code: u16, value: i32, time: std::time::SystemTime) -> InputEvent {
+    let since_epoch = time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    InputEvent::from(nix::libc::input_event {
+        time: nix::libc::timeval {
+            tv_sec: since_epoch.as_secs() as nix::libc::time_t,
+            tv_usec: since_epoch.subsec_micros() as nix::libc::suseconds_t,
+        },
+        type_: evdev::EventType::KEY.0,
+        code,
+        value,
+    })
+}
+
+fn send_rel(emitter: &mut dyn KeyEmitter, axis: RelativeAxisType, value: i32) {
+    let event = InputEvent::new(evdev::EventType::RELATIVE, axis.0, value);
+    emit_or_log("relative event", || emitter.emit_event(event));
+}
+
+fn send_chord_down(emitter: &mut dyn KeyEmitter, chord: &[u16], delay: Duration) {
+    let keys: Vec<(u16, i32)> = chord.iter().map(|code| (*code, KeyState::DOWN as i32)).collect();
+    send_keys(emitter, &keys, delay);
+}
+
+// Release in reverse so modifiers are let go after the main key
+fn send_chord_up(emitter: &mut dyn KeyEmitter, chord: &[u16], delay: Duration) {
+    let keys: Vec<(u16, i32)> = chord.iter().rev().map(|code| (*code, KeyState::UP as i32)).collect();
+    send_keys(emitter, &keys, delay);
+}
+
+// A fn key together with the keymap it activates
+struct Layer {
+    name: String,
+    fn_key: Key,
+    // Typed when fn_key is tapped instead of held, fn_key itself unless fn_tap_key is set
+    tap_key: Key,
+    // A tap of fn_key turns the layer on and the next one turns it off, instead of holding it
+    toggle: bool,
+    // Mapped keys pressed in SHIFT only count once held this long, shorter touches are dropped
+    min_hold: Option<Duration>,
+    keymap: HashMap<u16, Mapping>,
+}
+
+impl Layer {
+    fn from_config(
+        name: &str,
+        fn_key: &toml::Value,
+        tap_key: Option<&toml::Value>,
+        keymap: toml::value::Table,
+        layout: &layout::Layout,
+        aliases: &Aliases,
+    ) -> Result<Self, String> {
+        let fn_key = get_key(fn_key, "fn_key")?;
+        Ok(Layer {
+            name: name.to_string(),
+            fn_key,
+            tap_key: tap_key.map_or(Ok(fn_key), |key| get_key(key, "fn_tap_key"))?,
+            toggle: false,
+            min_hold: None,
+            keymap: get_keymap(keymap, layout, aliases).map_err(|e| format!("Layer {}: {}", name, e))?,
+        })
+    }
+}
+
+// A layer mapping its own fn_key can never do anything, that's always an error.
+// Emitting a fn_key is allowed but won't activate its layer. The default config
+// types its own fn_key with KEY_SEMICOLON, so only other layers' fn keys get a warning
+fn check_fn_keys(layers: &[Layer]) -> Result<(), String> {
+    for layer in layers {
+        if layer.keymap.contains_key(&layer.fn_key.code()) {
+            return Err(format!(
+                "Layer {} maps its own fn_key {:?}, pick another fn_key or remove the mapping",
+                layer.name, layer.fn_key
+            ));
+        }
+    }
+    for layer in layers {
+        let chords = layer.keymap.iter().flat_map(|(source, mapping)| {
+            mapping.outputs().filter_map(move |output| match output {
+                Output::Chord(chord) => Some((source, chord)),
+                _ => None,
+            })
+        });
+        for (source, chord) in chords {
+            for other in layers.iter().filter(|other| chord.contains(&other.fn_key.code())) {
+                if other.name == layer.name {
+                    info!(
+                        "Layer {} maps {:?} to its own fn_key {:?}, which is emitted as a plain key",
+                        layer.name,
+                        Key::new(*source),
+                        other.fn_key
+                    );
+                } else {
+                    warn!(
+                        "Layer {} maps {:?} to {:?}, the fn_key of layer {}. It's emitted as a plain key \
+                         and won't activate that layer",
+                        layer.name,
+                        Key::new(*source),
+                        other.fn_key,
+                        other.name
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// fn keys and the pause key are intercepted before any keymap is consulted,
+// so mapping them as a source never does what the user expects. A pause combo
+// only takes its keys while the others are held, so mapping those is fine
+fn keymap_conflicts(layers: &[Layer], pause_key: &[u16]) -> Vec<String> {
+    let mut conflicts = Vec::new();
+    for layer in layers {
+        for other in layers {
+            if layer.keymap.contains_key(&other.fn_key.code()) {
+                conflicts.push(format!(
+                    "Layer {} maps {:?}, which is the fn_key of layer {}",
+                    layer.name, other.fn_key, other.name
+                ));
+            }
+        }
+        if let [pause_key] = pause_key {
+            if layer.keymap.contains_key(pause_key) {
+                conflicts.push(format!(
+                    "Layer {} maps {:?}, which is the pause_key",
+                    layer.name,
+                    Key::new(*pause_key)
+                ));
+            }
+        }
+    }
+    conflicts
+}
+
+// Everything the StateMachine takes from the config file, rebuilt on reload
+struct Settings {
+    // Toggles pause when all of them are held, usually just one key
+    pause_key: Vec<u16>,
+    // Held together for escape_hold, tokey exits. None turns that off
+    escape_combo: Vec<u16>,
+    escape_hold: Option<Duration>,
+    // Every decision is appended here, relative paths are in the XDG state dir
+    log_file: Option<PathBuf>,
+    // The top level fn_key and keymap are always layer 0
+    layers: Vec<Layer>,
+    // With invert the top level keymap moves to this extra layer, which maps keys in IDLE
+    // and is never activated, leaving layer 0 with nothing to map while fn_key is held
+    idle_layer: Option<usize>,
+    timeout: Duration,
+    // Lowest and highest timeout when it follows typing speed, None keeps it fixed
+    adaptive_timeout: Option<(Duration, Duration)>,
+    emit_syn: bool,
+    // Two fn_key taps within this lock the layer, None disables locking
+    double_tap: Option<Duration>,
+    scroll_interval: Duration,
+    // Pointer step size on press, at full speed, and how long it takes to get there
+    pointer_speed: i32,
+    pointer_max_speed: i32,
+    pointer_accel: Duration,
+    // Never buffered or mapped, e.g. KEY_LEFTCTRL so ctrl combos work while navigating
+    passthrough: Vec<u16>,
+    // Keys that turn caps word off when pressed
+    caps_word_end: Vec<u16>,
+    // A lone fn_key tap maps just the next key press
+    oneshot: bool,
+    // A mapped key pressed before fn_key and released while deciding also taps its mapping
+    remap_prefn_release: bool,
+    // Pause after each event of a chord or buffer flush, zero sends them in one write
+    inter_key_delay: Duration,
+    // Send the keyboard's MSC_SCAN with each key instead of forwarding it as is
+    emit_scancode: bool,
+    // Leader sequences typed after a lone fn_key tap, and the chord each sends
+    sequences: HashMap<Vec<u16>, Vec<u16>>,
+    // How long SEQUENCE waits for the next key
+    sequence_timeout: Duration,
+    // A mapped key pressed first in DECIDE shifts right away instead of on its release
+    resolve_on_down: bool,
+    // How long a tap of a key with a double_tap waits for the second one
+    key_double_tap: Duration,
+    // Keys sent for exactly one input key carry its timestamp instead of the current time
+    preserve_timestamps: bool,
+    // fn keys go out as they're pressed and released, on top of activating their layer
+    passthrough_fn_hold: bool,
+    // Presses and releases this soon after a key's last one are switch chatter
+    debounce: Option<Duration>,
+    // A locked layer with no key pressed or released for this long unlocks itself
+    lock_idle_timeout: Option<Duration>,
+    // Keys DECIDE buffers before passing the oldest one through unmapped
+    event_buffer_size: usize,
+    // Keymaps replacing the default layer's for keys from the device with that name
+    device_keymaps: HashMap<String, HashMap<u16, Mapping>>,
+    // Keys sent as other keys whenever they'd go out unmapped, in any state
+    global_keymap: HashMap<u16, u16>,
+    // Keymaps for the default layer by window class. The active one is swapped
+    // with the default layer's keymap, so it holds the config's own keymap meanwhile
+    #[cfg(feature = "x11_profiles")]
+    profiles: HashMap<String, HashMap<u16, Mapping>>,
+    #[cfg(feature = "x11_profiles")]
+    active_profile: Option<String>,
+}
+
+// Outputs the virtual device has to declare on top of the grabbed devices' keys
+#[derive(Default)]
+struct ExtraOutputs {
+    buttons: Vec<Key>,
+    rel_axes: Vec<RelativeAxisType>,
+}
+
+impl Settings {
+    fn from_config(config: Config) -> Result<Self, String> {
+        let layout = get_layout(config.layout.as_deref());
+        let aliases = get_aliases(config.aliases.unwrap_or_default())?;
+        let pause_key = get_chord(&config.pause_key, &aliases)
+            .ok()
+            .filter(|keys| !keys.is_empty())
+            .ok_or_else(|| format!("Invalid pause_key: {}", config.pause_key))?;
+        let escape_combo = match &config.escape_combo {
+            Some(combo) => get_chord(combo, &aliases)
+                .ok()
+                .filter(|keys| !keys.is_empty())
+                .ok_or_else(|| format!("Invalid escape_combo: {}", combo))?,
+            None => get_chord(&DEFAULT_ESCAPE_COMBO.into(), &aliases)?,
+        };
+        let mut layers = vec![Layer::from_config(
+            "default",
+            &config.fn_key,
+            config.fn_tap_key.as_ref(),
+            config.keymap,
+            layout,
+            &aliases,
+        )?];
+        layers[0].min_hold = config.min_hold_ms.filter(|ms| *ms > 0).map(Duration::from_millis);
+        for (name, layer) in config.layers.unwrap_or_default() {
+            let mut layer = match layer {
+                toml::Value::Table(t) => t,
+                _ => return Err(format!("Layer {} must be a table", name)),
+            };
+            let fn_key = layer
+                .remove("fn_key")
+                .ok_or_else(|| format!("Layer {} is missing fn_key", name))?;
+            let keymap = match layer.remove("keymap") {
+                Some(toml::Value::Table(t)) => t,
+                Some(_) => return Err(format!("Layer {} keymap must be a table", name)),
+                None => toml::value::Table::new(),
+            };
+            let tap_key = layer.remove("fn_tap_key");
+            let toggle = match layer.remove("mode") {
+                None => false,
+                Some(mode) => match mode.as_str() {
+                    Some("hold") => false,
+                    Some("toggle") => true,
+                    _ => {
+                        return Err(format!("Layer {} mode must be \"hold\" or \"toggle\", got {}", name, mode))
+                    }
+                },
+            };
+            let min_hold = match layer.remove("min_hold_ms") {
+                None => None,
+                Some(ms) => match ms.as_integer() {
+                    Some(ms) if ms >= 0 => Some(ms as u64),
+                    _ => return Err(format!("Layer {} min_hold_ms must be a number of ms, got {}", name, ms)),
+                },
+            };
+            let mut layer = Layer::from_config(&name, &fn_key, tap_key.as_ref(), keymap, layout, &aliases)?;
+            layer.toggle = toggle;
+            layer.min_hold = min_hold.filter(|ms| *ms > 0).map(Duration::from_millis);
+            layers.push(layer);
+        }
+        for (i, layer) in layers.iter().enumerate() {
+            if let Some(other) = layers[..i].iter().find(|l| l.fn_key == layer.fn_key) {
+                return Err(format!(
+                    "Layers {} and {} share the fn_key {:?}",
+                    other.name, layer.name, layer.fn_key
+                ));
+            }
+        }
+        check_fn_keys(&layers)?;
+        let conflicts = keymap_conflicts(&layers, &pause_key);
+        if !conflicts.is_empty() {
+            match config.keymap_conflicts.as_deref().unwrap_or("warn") {
+                "warn" => {
+                    for conflict in &conflicts {
+                        warn!("{}", conflict);
+                    }
+                }
+                "error" => return Err(conflicts.join("\n")),
+                other => return Err(format!("Invalid keymap_conflicts: {}", other)),
+            }
+        }
+        let event_buffer_size = match config.event_buffer_size {
+            Some(0) => return Err("event_buffer_size must be at least 1".to_string()),
+            size => size.unwrap_or(DEFAULT_EVENT_BUFFER_SIZE),
+        };
+        #[cfg(feature = "x11_profiles")]
+        let profiles = config
+            .profiles
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(class, keymap)| match keymap {
+                toml::Value::Table(t) => get_keymap(t, layout, &aliases)
+                    .map(|keymap| (class.clone(), keymap))
+                    .map_err(|e| format!("Profile {}: {}", class, e)),
+                _ => Err(format!("Profile {} must be a table", class)),
+            })
+            .collect::<Result<_, _>>()?;
+        let global_keymap = get_global_keymap(config.global_keymap.unwrap_or_default(), &aliases)?;
+        let device_keymaps = config
+            .device_keymaps
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(device, keymap)| match keymap {
+                toml::Value::Table(t) => get_keymap(t, layout, &aliases)
+                    .map(|keymap| (device.clone(), keymap))
+                    .map_err(|e| format!("Device keymap {}: {}", device, e)),
+                _ => Err(format!("Device keymap {} must be a table", device)),
+            })
+            .collect::<Result<_, _>>()?;
+        let sequences = get_sequences(config.sequences.unwrap_or_default(), &aliases)?;
+        let passthrough = config
+            .passthrough_keys
+            .unwrap_or_default()
+            .iter()
+            .map(|key| get_key(key, "passthrough_keys").map(|k| k.code()))
+            .collect::<Result<_, _>>()?;
+        let caps_word_end = match config.caps_word_end {
+            Some(keys) => keys
+                .iter()
+                .map(|key| get_key(key, "caps_word_end").map(|k| k.code()))
+                .collect::<Result<_, _>>()?,
+            None => DEFAULT_CAPS_WORD_END.iter().map(|k| k.code()).collect(),
+        };
+        let mode_switch_timeout = get_mode_switch_timeout(config.mode_switch_timeout.as_ref())?;
+        let adaptive_timeout = get_adaptive_timeout(config.adaptive_timeout_ms.as_ref())?;
+        let pointer_speed = config.pointer_speed.unwrap_or(DEFAULT_POINTER_SPEED);
+        if pointer_speed < 1 {
+            return Err(format!("pointer_speed must be at least 1, got {}", pointer_speed));
+        }
+        let idle_layer = if config.invert.unwrap_or(false) {
+            let keymap = std::mem::take(&mut layers[0].keymap);
+            layers.push(Layer {
+                name: "inverted".to_string(),
+                fn_key: Key::KEY_RESERVED,
+                tap_key: Key::KEY_RESERVED,
+                toggle: false,
+                min_hold: None,
+                keymap,
+            });
+            Some(layers.len() - 1)
+        } else {
+            None
+        };
+
+        Ok(Settings {
+            pause_key,
+            escape_combo,
+            log_file: config.log_file.map(PathBuf::from),
+            escape_hold: Some(config.escape_hold_ms.unwrap_or(DEFAULT_ESCAPE_HOLD_MS))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            layers,
+            idle_layer,
+            timeout: mode_switch_timeout,
+            adaptive_timeout,
+            emit_syn: config.emit_syn.unwrap_or(false),
+            double_tap: config.double_tap_ms.map(Duration::from_millis),
+            scroll_interval: Duration::from_millis(
+                config.scroll_interval_ms.unwrap_or(DEFAULT_SCROLL_INTERVAL_MS)
+            ),
+            pointer_speed,
+            // Never slower than the starting speed
+            pointer_max_speed: config
+                .pointer_max_speed
+                .unwrap_or(DEFAULT_POINTER_MAX_SPEED)
+                .max(pointer_speed),
+            pointer_accel: Duration::from_millis(config.pointer_accel_ms.unwrap_or(DEFAULT_POINTER_ACCEL_MS)),
+            passthrough,
+            caps_word_end,
+            oneshot: config.oneshot.unwrap_or(false),
+            remap_prefn_release: config.remap_prefn_release.unwrap_or(false),
+            inter_key_delay: Duration::from_micros(config.inter_key_delay_us.unwrap_or(0)),
+            emit_scancode: config.emit_scancode.unwrap_or(false),
+            sequences,
+            sequence_timeout: Duration::from_millis(
+                config.sequence_timeout_ms.unwrap_or(DEFAULT_SEQUENCE_TIMEOUT_MS)
+            ),
+            resolve_on_down: config.resolve_on_down.unwrap_or(false),
+            event_buffer_size,
+            key_double_tap: Duration::from_millis(
+                config.key_double_tap_ms.unwrap_or(DEFAULT_KEY_DOUBLE_TAP_MS)
+            ),
+            preserve_timestamps: config.preserve_timestamps.unwrap_or(false),
+            passthrough_fn_hold: config.passthrough_fn_hold.unwrap_or(false),
+            debounce: config.debounce_ms.filter(|ms| *ms > 0).map(Duration::from_millis),
+            lock_idle_timeout: config.lock_idle_timeout_ms.filter(|ms| *ms > 0).map(Duration::from_millis),
+            device_keymaps,
+            global_keymap,
+            #[cfg(feature = "x11_profiles")]
+            profiles,
+            #[cfg(feature = "x11_profiles")]
+            active_profile: None,
+        })
+    }
+    
+    // Mouse buttons and axes some keymap emits, the virtual device has to declare them up front
+    fn extra_outputs(&self) -> ExtraOutputs {
+        let mut extra = ExtraOutputs::default();
+        let outputs = self
+            .layers
+            .iter()
+            .map(|layer| &layer.keymap)
+            .chain(self.device_keymaps.values())
+            .flat_map(|keymap| keymap.values())
+            .flat_map(Mapping::outputs);
+        for output in outputs {
+            match output {
+                Output::Chord(chord) => extra
+                    .buttons
+                    .extend(chord.iter().filter(|code| is_button(**code)).map(|code| Key::new(*code))),
+                Output::Rel(axis, _) => extra.rel_axes.push(*axis),
+                // libinput ignores relative motion from devices without a left button
+                Output::Pointer(axis, _) => {
+                    extra.rel_axes.push(*axis);
+                    extra.buttons.push(Key::BTN_LEFT);
+                }
+                Output::Text(_)
+                | Output::Sticky(_)
+                | Output::ClearSticky
+                | Output::CapsWord
+                | Output::Nothing => {}
+            }
+        }
+        // A mouse button fn_key is typed on a tap like any other
+        let tap_buttons = self.layers.iter().map(|layer| layer.tap_key).filter(|key| is_button(key.code()));
+        extra.buttons.extend(tap_buttons);
+        let global_buttons = self.global_keymap.values().filter(|code| is_button(**code));
+        extra.buttons.extend(global_buttons.map(|code| Key::new(*code)));
+        extra.buttons.sort_by_key(|b| b.code());
+        extra.buttons.dedup();
+        extra.rel_axes.sort_by_key(|a| a.0);
+        extra.rel_axes.dedup();
+        extra
+    }
+    
+    // Layers whose fn_key none of the grabbed devices has, e.g. a mouse button with only the keyboard grabbed
+    fn missing_fn_keys(&self, has_key: impl Fn(Key) -> bool) -> Vec<String> {
+        self.layers
+            .iter()
+            .filter(|layer| layer.fn_key != Key::KEY_RESERVED && !has_key(layer.fn_key))
+            .map(|layer| format!("{:?} (layer {})", layer.fn_key, layer.name))
+            .collect()
+    }
+    
+    // Use the profile for a window class as the default keymap, or the config's keymap if there's none
+    #[cfg(feature = "x11_profiles")]
+    fn switch_profile(&mut self, class: &str) {
+        let name = self
+            .profiles
+            .keys()
+            .find(|name| name.eq_ignore_ascii_case(class))
+            .cloned();
+        if name == self.active_profile {
+            return;
+        }
+        let default_layer = self.default_layer();
+        for profile in self.active_profile.iter().chain(name.iter()) {
+            std::mem::swap(
+                &mut self.layers[default_layer].keymap,
+                self.profiles.get_mut(profile).unwrap(),
+            );
+        }
+        info!("Using {} keymap", name.as_deref().unwrap_or("default"));
+        self.active_profile = name;
+    }
+    
+    // Pointer step for a key held this long, ramping linearly from pointer_speed to pointer_max_speed
+    fn pointer_step(&self, held: Duration) -> i32 {
+        if held >= self.pointer_accel {
+            return self.pointer_max_speed;
+        }
+        let ramp = (self.pointer_max_speed - self.pointer_speed) as f64;
+        self.pointer_speed + (ramp * held.as_secs_f64() / self.pointer_accel.as_secs_f64()) as i32
+    }
+    
+    // What tapping layer l's fn_key types, nothing when passthrough_fn_hold already sent the tap
+    fn tap(&self, l: usize) -> Vec<(u16, i32)> {
+        let layer = &self.layers[l];
+        if self.passthrough_fn_hold && layer.tap_key == layer.fn_key {
+            return Vec::new();
+        }
+        vec![(layer.tap_key.code(), KeyState::DOWN as i32), (layer.tap_key.code(), KeyState::UP as i32)]
+    }
+    
+    // The layer with the top level keymap
+    fn default_layer(&self) -> usize {
+        self.idle_layer.unwrap_or(0)
+    }
+    
+    fn layer_for_fn_key(&self, code: u16) -> Option<usize> {
+        self.layers.iter().position(|layer| layer.fn_key.code() == code)
+    }
+    
+    // What code maps to in a layer, None for unmapped and disabled keys.
+    // key_devices picks the device keymap of a held key
+    fn mapping(
+        &self,
+        layer: usize,
+        code: u16,
+        key_devices: &HashMap<u16, String>,
+        disabled: &HashSet<u16>,
+    ) -> Option<&Mapping> {
+        if disabled.contains(&code) {
+            return None;
+        }
+        let device_keymap = key_devices
+            .get(&code)
+            .filter(|_| layer == self.default_layer())
+            .and_then(|device| self.device_keymaps.get(device));
+        device_keymap.unwrap_or(&self.layers[layer].keymap).get(&code)
+    }
+}
+
+const MODIFIERS: [Key; 8] = [
+    Key::KEY_LEFTSHIFT,
+    Key::KEY_RIGHTSHIFT,
+    Key::KEY_LEFTCTRL,
+    Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTALT,
+    Key::KEY_RIGHTALT,
+    Key::KEY_LEFTMETA,
+    Key::KEY_RIGHTMETA,
+];
+
+fn is_modifier(code: u16) -> bool {
+    MODIFIERS.iter().any(|m| m.code() == code)
+}
+
+// Letter keys come in three runs, one per keyboard row
+fn is_letter(code: u16) -> bool {
+    (Key::KEY_Q.code()..=Key::KEY_P.code()).contains(&code)
+        || (Key::KEY_A.code()..=Key::KEY_L.code()).contains(&code)
+        || (Key::KEY_Z.code()..=Key::KEY_M.code()).contains(&code)
+}
+
+// A held key mapped to a relative axis
+struct Scroll {
+    source: u16,
+    axis: RelativeAxisType,
+    step: i32,
+    next: Instant,
+    // When a pointer key went down, its step is the direction scaled by pointer_step
+    pointer_since: Option<Instant>,
+}
+
+// How fn key holds were resolved, for tuning mode_switch_timeout
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Stats {
+    // fn_key released while still deciding
+    pub taps: u64,
+    // Layers that shifted, either by a key tapped inside the hold or by the timeout
+    pub shifts: u64,
+    // Shifts caused by the timeout
+    pub timeouts: u64,
+    pub keys_remapped: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} taps, {} shifts ({} by timeout), {} keys remapped",
+            self.taps, self.shifts, self.timeouts, self.keys_remapped
+        )
+    }
+}
+
+// Runtime state of one layer, so layers held at the same time don't share a buffer
+struct LayerState {
+    state: State,
+    start_time: Instant,
+    event_buffer: Vec<u16>,
+    // When fn_key was last tapped on its own, for double_tap_ms
+    last_tap: Option<Instant>,
+    // SHIFT was entered with a double tap and stays on until fn_key is tapped again
+    locked: bool,
+    // SHIFT was entered with a lone tap and only maps the next key press
+    oneshot: bool,
+    // Keys typed in SEQUENCE so far, and whether each was released yet.
+    // The held ones are also in event_buffer so their release is routed here
+    sequence: Vec<(u16, bool)>,
+    // Modifiers toggled on by sticky mappings, in the order they were turned on
+    sticky: Vec<u16>,
+    // Mapped keys pressed in SHIFT that aren't held for min_hold yet, with when they went down
+    pending_holds: Vec<(u16, Instant)>,
+}
+
+impl LayerState {
+    fn new(event_buffer_size: usize) -> Self {
+        LayerState {
+            state: State::IDLE,
+            start_time: Instant::now(),
+            event_buffer: Vec::with_capacity(event_buffer_size),
+            last_tap: None,
+            locked: false,
+            oneshot: false,
+            sequence: Vec::new(),
+            sticky: Vec::new(),
+            pending_holds: Vec::new(),
+        }
+    }
+}
+
+// The output device with scancodes added if emit_scancode is set, decisions logged if log_file
+// is, and its pressed keys tracked
+fn wrap_emitter(emitter: Box<dyn KeyEmitter>, settings: &Settings) -> Box<dyn KeyEmitter> {
+    let emitter = if settings.emit_scancode {
+        Box::new(ScancodeEmitter {
+            inner: emitter,
+            scancodes: HashMap::new(),
+        })
+    } else {
+        emitter
+    };
+    let emitter = match settings.log_file.as_deref().and_then(open_audit_log) {
+        Some(log) => Box::new(AuditEmitter { inner: emitter, log, emitted: Vec::new() }),
+        None => emitter,
+    };
+    Box::new(PressedEmitter { inner: emitter, pressed: Vec::new() })
+}
+
+struct StateMachine {
+    emitter: Box<dyn KeyEmitter>,
+    settings: Settings,
+    layers: Vec<LayerState>,
+    // Non-IDLE layers in activation order, the last one receives new key presses
+    active: Vec<usize>,
+    // Real modifier keys currently held down on the input devices
+    held_modifiers: Vec<u16>,
+    // Keys of pause_key currently held down
+    pause_held: Vec<u16>,
+    // Keys of escape_combo currently held down, and since when all of them are
+    escape_held: Vec<u16>,
+    escape_since: Option<Instant>,
+    // When each key last went down or up, for debounce
+    last_transition: HashMap<u16, Instant>,
+    // When any key last did anything, for lock_idle_timeout_ms
+    last_activity: Instant,
+    // When a key last went down and the times between the last few presses, for adaptive_timeout_ms
+    last_key_down: Option<Instant>,
+    key_intervals: std::collections::VecDeque<Duration>,
+    scrolls: Vec<Scroll>,
+    // Keys pressed through a one-shot layer that has since gone IDLE, with the layer
+    // that mapped them so their release matches
+    oneshot_held: Vec<(usize, u16)>,
+    // Mapped keys held down with a modifier condition, and which one
+    conditional_held: Vec<(u16, usize)>,
+    // Name of the device the events being run come from, when it has its own keymap
+    source: Option<String>,
+    // MSC_SCAN waiting for the key event it belongs to, with emit_scancode
+    pending_scancode: Option<i32>,
+    // The event being run with preserve_timestamps, keys sent for it get its time
+    stamp: Option<InputEvent>,
+    // Keys that completed a sequence while held, their repeats and release are dropped
+    swallowed: Vec<u16>,
+    // Keys that went down unmapped when a layer gave up on them, their release goes out unmapped too
+    raw_held: Vec<u16>,
+    // Keys pressed through global_keymap and what they went out as, released the same way
+    global_held: Vec<(u16, u16)>,
+    // Held keys that came from a device in device_keymaps, so their release maps the same way
+    key_devices: HashMap<u16, String>,
+    // Source keys turned off over dbus, unmapped in every layer until the next reload
+    disabled: HashSet<u16>,
+    // Letters typed outside the layer get shift added until a caps_word_end key
+    caps_word: bool,
+    // Letters pressed with caps word's shift, released with it even if caps word ended meanwhile
+    caps_word_held: Vec<u16>,
+    // Layer and key of a first tap that waits for a second one, and when it was released
+    pending_tap: Option<(usize, u16, Instant)>,
+    stats: Stats,
+    paused: bool,
+    // Where paused is kept across restarts, None to not persist it
+    paused_file: Option<PathBuf>,
+    // Class of the focused window, to pick the profile again after a reload
+    #[cfg(feature = "x11_profiles")]
+    window_class: String,
+    messenger: Option<tokey_ipc::Messenger>
+}
+
+impl StateMachine {
+    fn new(
+        emitter: Box<dyn KeyEmitter>,
+        settings: Settings,
+        paused_file: Option<PathBuf>,
+        messenger: Option<tokey_ipc::Messenger>
+    ) -> Self {
+        let layers = settings
+            .layers
+            .iter()
+            .map(|_| LayerState::new(settings.event_buffer_size))
+            .collect();
+        let emitter = wrap_emitter(emitter, &settings);
+        let paused = paused_file.as_deref().is_some_and(read_paused);
+        if let Some(messenger) = &messenger {
+            messenger.set_paused(paused);
+        }
+        
+        StateMachine {
+            emitter,
+            settings,
+            layers,
+            active: Vec::new(),
+            held_modifiers: Vec::new(),
+            pause_held: Vec::new(),
+            escape_held: Vec::new(),
+            escape_since: None,
+            last_transition: HashMap::new(),
+            last_activity: Instant::now(),
+            last_key_down: None,
+            key_intervals: std::collections::VecDeque::with_capacity(ADAPTIVE_SAMPLES),
+            scrolls: Vec::new(),
+            oneshot_held: Vec::new(),
+            conditional_held: Vec::new(),
+            source: None,
+            pending_scancode: None,
+            stamp: None,
+            swallowed: Vec::new(),
+            raw_held: Vec::new(),
+            global_held: Vec::new(),
+            key_devices: HashMap::new(),
+            disabled: HashSet::new(),
+            caps_word: false,
+            caps_word_held: Vec::new(),
+            pending_tap: None,
+            stats: Stats::default(),
+            paused,
+            paused_file,
+            #[cfg(feature = "x11_profiles")]
+            window_class: String::new(),
+            messenger}
+    }
+    
+    // Swap in a freshly parsed config, keeping the old one if it's invalid
+    fn reload(&mut self, config: Config) -> Result<(), String> {
+        let mut settings = Settings::from_config(config)?;
+        // The emitter was set up for the old value, it only changes with a restart
+        settings.emit_scancode = self.settings.emit_scancode;
+        // Let go of what the old keymap pressed while it can still be looked up
+        self.release_all();
+        self.settings = settings;
+        self.reset();
+        self.disabled.clear();
+        #[cfg(feature = "x11_profiles")]
+        self.settings.switch_profile(&self.window_class);
+        Ok(())
+    }
+    
+    // Swap in just the top level keymap from a freshly parsed config, everything else stays
+    fn reload_keymap(&mut self, config: Config) -> Result<(), String> {
+        let aliases = get_aliases(config.aliases.unwrap_or_default())?;
+        let mut keymap = get_keymap(config.keymap, get_layout(config.layout.as_deref()), &aliases)?;
+        let l = self.settings.default_layer();
+        // Keys held with the old keymap have to be released with it
+        self.release_layers();
+        std::mem::swap(&mut self.settings.layers[l].keymap, &mut keymap);
+        if let Err(e) = check_fn_keys(&self.settings.layers) {
+            self.settings.layers[l].keymap = keymap;
+            return Err(e);
+        }
+        // While a profile is active the config's keymap is kept in its place
+        #[cfg(feature = "x11_profiles")]
+        if let Some(profile) = self.settings.active_profile.clone() {
+            std::mem::swap(&mut self.settings.layers[l].keymap, &mut keymap);
+            self.settings.profiles.insert(profile, keymap);
+        }
+        Ok(())
+    }
+    
+    fn set_mapping_enabled(&mut self, code: u16, enabled: bool) {
+        // Keys held with the mapping still in place have to be released with it
+        self.release_layers();
+        if enabled {
+            self.disabled.remove(&code);
+        } else {
+            self.disabled.insert(code);
+        }
+        info!("{} mapping of {:?}", if enabled { "Enabled" } else { "Disabled" }, Key::new(code));
+    }
+    
+    #[cfg(feature = "x11_profiles")]
+    fn set_window_class(&mut self, class: String) {
+        // Keys held in the old keymap have to be released with it
+        self.release_layers();
+        self.settings.switch_profile(&class);
+        self.window_class = class;
+    }
+    
+    // Swap in a new output device, nothing is held on it yet
+    fn set_emitter(&mut self, emitter: Box<dyn KeyEmitter>) {
+        self.emitter = wrap_emitter(emitter, &self.settings);
+        self.reset();
+    }
+    
+    // Start over as if no key was ever pressed
+    fn reset(&mut self) {
+        self.pending_scancode = None;
+        self.layers = self
+            .settings
+            .layers
+            .iter()
+            .map(|_| LayerState::new(self.settings.event_buffer_size))
+            .collect();
+        self.active.clear();
+        self.held_modifiers.clear();
+        self.pause_held.clear();
+        self.escape_held.clear();
+        self.escape_since = None;
+        self.scrolls.clear();
+        self.oneshot_held.clear();
+        self.conditional_held.clear();
+        self.swallowed.clear();
+        self.raw_held.clear();
+        self.global_held.clear();
+        self.key_devices.clear();
+        self.caps_word_held.clear();
+        self.pending_tap = None;
+        if let Some(messenger) = &self.messenger {
+            messenger.set_state(self.state().name());
+        }
+    }
+    
+    fn run(&mut self, ev: InputEvent) -> bool {
+        let previous = self.state();
+        let previous_stats = self.stats;
+        self.last_activity = Instant::now();
+        if let Some(scancode) = self.pending_scancode.take() {
+            self.emitter.learn_scancode(ev.code(), scancode);
+        }
+        let key_state = match KeyState::try_from(ev.value()) {
+            Ok(key_state) => key_state,
+            Err(err) => {
+                warn!("Dropping {:?}: {}", Key::new(ev.code()), err);
+                return false;
+            }
+        };
+        if self.bounced(ev.code(), key_state) {
+            debug!("Dropping {:?} {}, it bounced", Key::new(ev.code()), ev.value());
+            self.emitter.report(Some(ev), self.state().name());
+            return false;
+        }
+        if self.settings.pause_key.contains(&ev.code()) {
+            match key_state {
+                KeyState::DOWN => self.pause_held.push(ev.code()),
+                KeyState::UP => self.pause_held.retain(|c| *c != ev.code()),
+                KeyState::REPEAT => {}
+            }
+        }
+        if self.settings.escape_combo.contains(&ev.code()) {
+            match key_state {
+                KeyState::DOWN => self.escape_held.push(ev.code()),
+                KeyState::UP => self.escape_held.retain(|c| *c != ev.code()),
+                KeyState::REPEAT => {}
+            }
+            let held = self.settings.escape_combo.iter().all(|code| self.escape_held.contains(code));
+            match (held, self.escape_since) {
+                (true, None) => self.escape_since = Some(Instant::now()),
+                (false, _) => self.escape_since = None,
+                (true, Some(_)) => {}
+            }
+        }
+        // A modifier still counts as held while its own release is handled, keys it settles
+        // were pressed with it down
+        if is_modifier(ev.code()) && key_state == KeyState::DOWN {
+            self.held_modifiers.push(ev.code());
+        }
+        if key_state == KeyState::DOWN {
+            self.note_key_down(Instant::now());
+        }
+        // Keys map by the device they were pressed on until they go up again
+        if key_state == KeyState::DOWN {
+            match &self.source {
+                Some(device) => self.key_devices.insert(ev.code(), device.clone()),
+                None => self.key_devices.remove(&ev.code()),
+            };
+            if self.caps_word && self.settings.caps_word_end.contains(&ev.code()) {
+                debug!("caps word off");
+                self.caps_word = false;
+            }
+        }
+        if key_state == KeyState::DOWN && self.pending_tap.is_some_and(|(_, code, _)| code != ev.code()) {
+            // Another key, so the waiting tap stays single and goes out first
+            self.flush_pending_tap();
+        }
+        if self.settings.passthrough_fn_hold
+            && !self.paused
+            && key_state != KeyState::REPEAT
+            && self.settings.layer_for_fn_key(ev.code()).is_some()
+        {
+            // Whatever the layer makes of it, apps see fn_key held for as long as it really is
+            send_key_i32(self.emitter.as_mut(), ev.code(), ev.value());
+        }
+        let layer_states: Vec<State> = if logging::enabled(logging::Level::Debug) {
+            self.layers.iter().map(|layer| layer.state).collect()
+        } else {
+            Vec::new()
+        };
+        self.stamp = Some(ev).filter(|_| self.settings.preserve_timestamps);
+        let handled = if self.release_oneshot(&ev) {
+            true
+        } else if let Some(i) = self.swallowed.iter().position(|code| *code == ev.code()) {
+            if key_state == KeyState::UP {
+                self.swallowed.remove(i);
+            }
+            true
+        } else if key_state == KeyState::UP && self.release_raw(ev.code()) {
+            true
+        } else {
+            match self.route(&ev) {
+                Some(l) => match self.layers[l].state {
+                    State::IDLE => {self.state_idle(ev, key_state)}
+                    State::DECIDE => {self.state_decide(l, ev, key_state)}
+                    State::SHIFT => {self.state_shift(l, ev, key_state)}
+                    State::SEQUENCE => {self.state_sequence(l, ev, key_state)}
+                },
+                None => {self.state_idle(ev, key_state)}
+            }
+        };
+        self.stamp = None;
+        if is_modifier(ev.code()) && key_state == KeyState::UP {
+            self.held_modifiers.retain(|c| *c != ev.code());
+        }
+        for (l, before) in layer_states.into_iter().enumerate() {
+            if self.layers[l].state != before {
+                debug!(
+                    "layer {}: {} -> {}",
+                    self.settings.layers[l].name,
+                    before.name(),
+                    self.layers[l].state.name()
+                );
+            }
+        }
+        if self.state() != previous {
+            if let Some(messenger) = &self.messenger {
+                messenger.set_state(self.state().name());
+            }
+        }
+        if self.stats != previous_stats {
+            if let Some(messenger) = &self.messenger {
+                messenger.set_stats(self.stats);
+            }
+        }
+        if key_state == KeyState::UP {
+            self.key_devices.remove(&ev.code());
+        }
+        self.emitter.report(Some(ev), self.state().name());
+        handled
+    }
+    
+    // Where the next events come from, picks the device's keymap if it has one
+    fn set_source(&mut self, device: Option<&str>) {
+        self.source = device
+            .filter(|device| self.settings.device_keymaps.contains_key(*device))
+            .map(str::to_string);
+    }
+    
+    // Whether a press or release came within debounce of the key's last one, repeats always count
+    fn bounced(&mut self, code: u16, key_state: KeyState) -> bool {
+        let Some(debounce) = self.settings.debounce else { return false };
+        if key_state == KeyState::REPEAT {
+            return false;
+        }
+        let now = Instant::now();
+        if self.last_transition.get(&code).is_some_and(|last| now.duration_since(*last) < debounce) {
+            return true;
+        }
+        self.last_transition.insert(code, now);
+        false
+    }
+    
+    // Release a key that was mapped by a one-shot layer, false if ev isn't one
+    fn release_oneshot(&mut self, ev: &InputEvent) -> bool {
+        if ev.value() != KeyState::UP as i32 {
+            return false;
+        }
+        match self.oneshot_held.iter().position(|(_, code)| *code == ev.code()) {
+            Some(i) => {
+                let (l, code) = self.oneshot_held.remove(i);
+                self.release_mapped(l, code);
+                self.syn();
+                true
+            }
+            None => false,
+        }
+    }
+    
+    // Release a key held from a buffer flush as is, false if code isn't one
+    fn release_raw(&mut self, code: u16) -> bool {
+        match self.raw_held.iter().position(|c| *c == code) {
+            Some(i) => {
+                self.raw_held.remove(i);
+                let out = self.global_code(code, KeyState::UP as i32);
+                send_key_up(self.emitter.as_mut(), out);
+                self.syn();
+                true
+            }
+            None => false,
+        }
+    }
+    
+    // State of the layer receiving new key presses, IDLE when no layer is active
+    fn state(&self) -> State {
+        self.active.last().map_or(State::IDLE, |&l| self.layers[l].state)
+    }
+    
+    // Pick the active layer that should handle ev, None if it's for state_idle
+    fn route(&self, ev: &InputEvent) -> Option<usize> {
+        let code = ev.code();
+        if let Some(l) = self.settings.layer_for_fn_key(code) {
+            // An idle layer's fn key activates it, even on top of another active layer
+            return self.active.iter().copied().find(|&a| a == l);
+        }
+        if let Some(idle) = self.settings.idle_layer {
+            // Keys pressed through the inverted keymap stay with it until released
+            if !is_down(ev.value()) && self.layers[idle].event_buffer.contains(&code) {
+                return Some(idle);
+            }
+        }
+        if ev.value() == KeyState::UP as i32 {
+            // Releases go back to the layer that buffered the press
+            if let Some(l) = self.active.iter().rev().copied().find(|&a| {
+                self.layers[a].event_buffer.contains(&code)
+            }) {
+                return Some(l);
+            }
+        }
+        self.active.last().copied()
+    }
+    
+    fn syn(&mut self) {
+        if self.settings.emit_syn {
+            emit_or_log("SYN_REPORT", || self.emitter.syn());
+        }
+    }
+    
+    // What code maps to in layer l, for the device it was pressed on
+    fn mapping(&self, l: usize, code: u16) -> Option<&Mapping> {
+        self.settings.mapping(l, code, &self.key_devices, &self.disabled)
+    }
+    
+    // How long a layer's fn key must be held before shifting, given the first key pressed after it
+    // A mapping's own timeout wins over the layer wide one
+    fn timeout_for(&self, l: usize, first: Option<&u16>) -> Duration {
+        first
+            .and_then(|code| self.mapping(l, *code))
+            .and_then(|m| m.timeout)
+            .unwrap_or_else(|| self.effective_timeout())
+    }
+    
+    // The modifier condition code was pressed with, if one applied
+    fn held_condition(&self, code: u16) -> Option<usize> {
+        self.conditional_held.iter().find(|(c, _)| *c == code).map(|(_, i)| *i)
+    }
+    
+    // Press what code maps to in layer l, or code itself if it's unmapped
+    fn press_mapped(&mut self, l: usize, code: u16) {
+        // Picked on press so the release matches even if the modifier goes up first
+        let mut condition = self.mapping(l, code).and_then(|m| m.condition(&self.held_modifiers));
+        if let Some(double_tap) = self.mapping(l, code).and_then(Mapping::double_tap) {
+            let window = self.settings.key_double_tap;
+            if self.pending_tap.is_some_and(|(pl, pc, at)| pl == l && pc == code && at.elapsed() <= window) {
+                self.pending_tap = None;
+                condition = Some(double_tap);
+            } else {
+                // A first tap, only sent once it's clear no second one follows
+                self.flush_pending_tap();
+                return;
+            }
+        }
+        self.press_condition(l, code, condition);
+    }
+    
+    fn press_condition(&mut self, l: usize, code: u16, condition: Option<usize>) {
+        if self.mapping(l, code).is_some() {
+            self.stats.keys_remapped += 1;
+        }
+        if let Some(i) = condition {
+            self.conditional_held.push((code, i));
+        }
+        let mapping = self.settings.mapping(l, code, &self.key_devices, &self.disabled);
+        match mapping.map(|m| m.output(condition)) {
+            Some(Output::Chord(chord)) if chord.len() == 1 && self.layers[l].sticky.is_empty() => {
+                self.send_source_key(code, chord[0], KeyState::DOWN as i32);
+            }
+            Some(Output::Chord(chord)) => {
+                send_chord_down(self.emitter.as_mut(), &self.layers[l].sticky, self.settings.inter_key_delay);
+                send_chord_down(self.emitter.as_mut(), chord, self.settings.inter_key_delay);
+            }
+            Some(Output::Rel(axis, step)) => {
+                send_rel(self.emitter.as_mut(), *axis, *step);
+                self.scrolls.push(Scroll {
+                    source: code,
+                    axis: *axis,
+                    step: *step,
+                    next: Instant::now() + self.settings.scroll_interval,
+                    pointer_since: None,
+                });
+            }
+            Some(Output::Pointer(axis, direction)) => {
+                let now = Instant::now();
+                send_rel(self.emitter.as_mut(), *axis, direction * self.settings.pointer_speed);
+                self.scrolls.push(Scroll {
+                    source: code,
+                    axis: *axis,
+                    step: *direction,
+                    next: now + POINTER_INTERVAL,
+                    pointer_since: Some(now),
+                });
+            }
+            Some(Output::Text(chords)) => {
+                for chord in chords {
+                    send_chord_down(self.emitter.as_mut(), chord, self.settings.inter_key_delay);
+                    send_chord_up(self.emitter.as_mut(), chord, self.settings.inter_key_delay);
+                }
+            }
+            Some(Output::Sticky(modifier)) => {
+                let sticky = &mut self.layers[l].sticky;
+                if let Some(i) = sticky.iter().position(|m| m == modifier) {
+                    sticky.remove(i);
+                    // A mapped key may still be holding it down
+                    send_key_up(self.emitter.as_mut(), *modifier);
+                } else {
+                    sticky.push(*modifier);
+                }
+            }
+            Some(Output::CapsWord) => self.caps_word = !self.caps_word,
+            Some(Output::Nothing) => {}
+            Some(Output::ClearSticky) => {
+                let sticky = std::mem::take(&mut self.layers[l].sticky);
+                send_chord_up(self.emitter.as_mut(), &sticky, self.settings.inter_key_delay);
+            }
+            None => self.pass_key(code, KeyState::DOWN as i32),
+        }
+    }
+    
+    fn release_mapped(&mut self, l: usize, code: u16) {
+        let condition = self.held_condition(code);
+        let double_tap = self.mapping(l, code).and_then(Mapping::double_tap);
+        if double_tap.is_some() && condition != double_tap {
+            // The end of a first tap, wait for a second one
+            self.pending_tap = Some((l, code, Instant::now()));
+            return;
+        }
+        self.release_condition(l, code, condition);
+    }
+    
+    // Type the single tap of a key with a double_tap that wasn't tapped again
+    fn flush_pending_tap(&mut self) {
+        if let Some((l, code, _)) = self.pending_tap.take() {
+            let condition = self.mapping(l, code).and_then(|m| m.condition(&self.held_modifiers));
+            // Typed late, it doesn't get the time of whatever is being run
+            let stamp = self.stamp.take();
+            self.press_condition(l, code, condition);
+            self.release_condition(l, code, condition);
+            self.stamp = stamp;
+            self.syn();
+        }
+    }
+    
+    fn release_condition(&mut self, l: usize, code: u16, condition: Option<usize>) {
+        self.conditional_held.retain(|(c, _)| *c != code);
+        let mapping = self.settings.mapping(l, code, &self.key_devices, &self.disabled);
+        match mapping.map(|m| m.output(condition)) {
+            Some(Output::Chord(chord)) if chord.len() == 1 && self.layers[l].sticky.is_empty() => {
+                self.send_source_key(code, chord[0], KeyState::UP as i32);
+            }
+            Some(Output::Chord(chord)) => {
+                send_chord_up(self.emitter.as_mut(), chord, self.settings.inter_key_delay);
+                send_chord_up(self.emitter.as_mut(), &self.layers[l].sticky, self.settings.inter_key_delay);
+            }
+            Some(Output::Rel(..) | Output::Pointer(..)) => self.scrolls.retain(|s| s.source != code),
+            // Typed completely on press, stickies only change state
+            Some(
+                Output::Text(_) | Output::Sticky(_) | Output::ClearSticky | Output::CapsWord | Output::Nothing,
+            ) => {}
+            None => self.pass_key(code, KeyState::UP as i32),
+        }
+    }
+    
+    // Release everything still held in layer l's buffer, last pressed first like a chord
+    fn release_buffered(&mut self, l: usize) {
+        for code in self.layers[l].event_buffer.clone().into_iter().rev() {
+            self.release_mapped(l, code);
+        }
+    }
+    
+    // Release whatever the active layers pressed and return them to IDLE. Keys buffered
+    // in DECIDE were never pressed, their physical release later goes out unmapped
+    fn release_layers(&mut self) {
+        for l in self.active.clone() {
+            if self.layers[l].state == State::SHIFT {
+                self.release_buffered(l);
+            }
+            self.enter_idle(l);
+        }
+        for (l, code) in std::mem::take(&mut self.oneshot_held) {
+            self.release_mapped(l, code);
+        }
+        if let Some(idle) = self.settings.idle_layer {
+            self.release_buffered(idle);
+            self.enter_idle(idle);
+        }
+        self.scrolls.clear();
+        self.syn();
+    }
+    
+    // epoll timeout in ms until the next scroll repeat, sequence timeout, min_hold or double tap
+    // window is due, -1 when there's none
+    fn poll_timeout(&self) -> isize {
+        let sequence_deadlines = self
+            .layers
+            .iter()
+            .filter(|layer| layer.state == State::SEQUENCE)
+            .map(|layer| layer.start_time + self.settings.sequence_timeout);
+        let hold_deadlines = self.active.iter().flat_map(|&l| {
+            let min_hold = self.settings.layers[l].min_hold.unwrap_or_default();
+            self.layers[l].pending_holds.iter().map(move |(_, down)| *down + min_hold)
+        });
+        let tap_deadline = self.pending_tap.map(|(_, _, at)| at + self.settings.key_double_tap);
+        let escape_deadline = self
+            .escape_since
+            .zip(self.settings.escape_hold)
+            .map(|(since, hold)| since + hold);
+        let lock_deadline = self
+            .settings
+            .lock_idle_timeout
+            .filter(|_| self.active.iter().any(|&l| self.layers[l].locked))
+            .map(|timeout| self.last_activity + timeout);
+        let deadlines = self
+            .scrolls
+            .iter()
+            .map(|s| s.next)
+            .chain(sequence_deadlines)
+            .chain(hold_deadlines)
+            .chain(tap_deadline)
+            .chain(lock_deadline)
+            .chain(escape_deadline);
+        deadlines.min().map_or(-1, |next| {
+            next.saturating_duration_since(Instant::now()).as_micros().div_ceil(1000) as isize
+        })
+    }
+    
+    // Repeat the scroll steps of held keys that are due, give up on sequences that timed out,
+    // press keys once they're held for min_hold, type taps that weren't doubled and unlock
+    // layers left locked for lock_idle_timeout_ms
+    fn tick(&mut self) {
+        for l in self.active.clone() {
+            if self.layers[l].state == State::SEQUENCE
+                && self.layers[l].start_time.elapsed() >= self.settings.sequence_timeout
+            {
+                self.abandon_sequence(l);
+            }
+        }
+        let now = Instant::now();
+        let idle = self
+            .settings
+            .lock_idle_timeout
+            .is_some_and(|timeout| now.duration_since(self.last_activity) >= timeout);
+        for l in self.active.clone() {
+            if idle && self.layers[l].locked {
+                debug!("layer {} unlocked, no keys for lock_idle_timeout_ms", self.settings.layers[l].name);
+                self.release_buffered(l);
+                self.syn();
+                self.enter_idle(l);
+            }
+        }
+        if self.pending_tap.is_some_and(|(_, _, at)| now.duration_since(at) > self.settings.key_double_tap) {
+            self.flush_pending_tap();
+        }
+        for l in self.active.clone() {
+            let Some(min_hold) = self.settings.layers[l].min_hold else { continue };
+            let (held, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.layers[l].pending_holds)
+                .into_iter()
+                .partition(|(_, down)| now.duration_since(*down) >= min_hold);
+            self.layers[l].pending_holds = pending;
+            for (code, _) in &held {
+                self.layers[l].event_buffer.push(*code);
+                self.press_mapped(l, *code);
+            }
+            if !held.is_empty() {
+                self.syn();
+            }
+        }
+        let mut scrolled = false;
+        for scroll in self.scrolls.iter_mut().filter(|s| s.next <= now) {
+            match scroll.pointer_since {
+                Some(since) => {
+                    let step = scroll.step * self.settings.pointer_step(now.duration_since(since));
+                    send_rel(self.emitter.as_mut(), scroll.axis, step);
+                    scroll.next = now + POINTER_INTERVAL;
+                }
+                None => {
+                    send_rel(self.emitter.as_mut(), scroll.axis, scroll.step);
+                    scroll.next = now + self.settings.scroll_interval;
+                }
+            }
+            scrolled = true;
+        }
+        if scrolled {
+            self.syn();
+        }
+        self.emitter.report(None, self.state().name());
+    }
+    
+    fn enter_idle(&mut self, l: usize) {
+        self.layers[l].event_buffer.clear();
+        self.layers[l].state = State::IDLE;
+        self.layers[l].locked = false;
+        self.layers[l].oneshot = false;
+        self.layers[l].sequence.clear();
+        self.layers[l].sticky.clear();
+        self.layers[l].pending_holds.clear();
+        self.active.retain(|&a| a != l);
+    }
+    
+    // A fresh hold of fn_key, nothing from an earlier one carries over
+    fn enter_decide(&mut self, l: usize) {
+        let layer = &mut self.layers[l];
+        layer.event_buffer.clear();
+        layer.sequence.clear();
+        layer.pending_holds.clear();
+        layer.locked = false;
+        layer.oneshot = false;
+        layer.start_time = Instant::now();
+        layer.state = State::DECIDE;
+        self.active.retain(|&a| a != l);
+        self.active.push(l);
+    }
+    
+    fn state_idle(&mut self, ev: InputEvent, key_state: KeyState) -> bool {
+        let ev_code = ev.code();
+        let ev_value = ev.value();
+        if self.active.is_empty()
+            && self.settings.pause_key.contains(&ev_code)
+            && is_down(ev_value)
+            && self.settings.pause_key.iter().all(|code| self.pause_held.contains(code))
+        {
+            // The other keys of a combo already went out, this one never does
+            self.swallowed.push(ev_code);
+            self.toggle_paused();
+            return true;
+        } else if let Some(l) = self.settings.layer_for_fn_key(ev_code) {
+            if !self.paused && is_down(ev_value) {
+                self.enter_decide(l);
+                if self.settings.layers[l].toggle {
+                    // Straight to a locked SHIFT, the next tap of fn_key turns it off like
+                    // unlocking. This tap's release and repeats don't count
+                    self.layers[l].locked = true;
+                    self.layers[l].state = State::SHIFT;
+                    self.swallowed.push(ev_code);
+                }
+                return true;
+            }
+        }
+        if let Some(idle) = self.settings.idle_layer {
+            // The inverted keymap maps here as if its layer was shifted, keys it pressed are
+            // released through it
+            let held = self.layers[idle].event_buffer.contains(&ev_code);
+            if held || (!self.paused && is_down(ev_value) && self.mapping(idle, ev_code).is_some()) {
+                return self.state_shift(idle, ev, key_state);
+            }
+        }
+        
+        // Wrapped in shift like a sticky modifier
+        let shifted = [Key::KEY_LEFTSHIFT.code(), ev_code];
+        let caps_word_held = self.caps_word_held.iter().position(|c| *c == ev_code);
+        if self.caps_word && is_letter(ev_code) && ev_value == KeyState::DOWN as i32 {
+            self.caps_word_held.push(ev_code);
+            send_chord_down(self.emitter.as_mut(), &shifted, self.settings.inter_key_delay);
+        } else if let (Some(i), true) = (caps_word_held, ev_value == KeyState::UP as i32) {
+            self.caps_word_held.remove(i);
+            send_chord_up(self.emitter.as_mut(), &shifted, self.settings.inter_key_delay);
+        } else {
+            self.pass_key(ev_code, ev_value);
+        }
+        false
+    }
+    
+    fn state_decide(&mut self, l: usize, ev: InputEvent, key_state: KeyState) -> bool {
+        if self.settings.passthrough.contains(&ev.code()) {
+            // Doesn't count towards deciding, as if fn_key wasn't held
+            self.pass_key(ev.code(), ev.value());
+            return false;
+        }
+        // Whatever goes out from here is the buffer being flushed
+        self.stamp = None;
+        let current_time = Instant::now();
+        let first = self.layers[l].event_buffer.first();
+        let timeout = self.timeout_for(l, first);
+        if current_time.duration_since(self.layers[l].start_time) >= timeout {
+            self.shift_buffered(l);
+            self.stats.timeouts += 1;
+            return self.state_shift(l, ev, key_state);
+        } else {
+            if is_modifier(ev.code()) && self.mapping(l, ev.code()).is_none() {
+                // Real modifiers go out right away so they're already down when a
+                // mapped key is emitted, e.g. shift+LEFT for selecting. Keys pressed
+                // with one held are mapped before it goes up, or shift+HOME would
+                // come out as a plain HOME after the shift release
+                if key_state == KeyState::UP && !self.layers[l].event_buffer.is_empty() {
+                    self.shift_buffered(l);
+                }
+                self.pass_key(ev.code(), ev.value());
+                return false;
+            }
+            let layer = &mut self.layers[l];
+            match key_state {
+                KeyState::DOWN => { 
+                    let code = ev.code();
+                    if self.settings.resolve_on_down
+                        && layer.event_buffer.is_empty()
+                        && self.settings.mapping(l, code, &self.key_devices, &self.disabled).is_some()
+                    {
+                        // Pressed while fn_key is held with nothing else pending, so it's
+                        // taken as mapped now and released on its real UP in SHIFT
+                        layer.event_buffer.push(code);
+                        layer.state = State::SHIFT;
+                        self.stats.shifts += 1;
+                        self.press_mapped(l, code);
+                        self.syn();
+                        return true;
+                    }
+                    if layer.event_buffer.len() >= self.settings.event_buffer_size {
+                        // Buffer is full, pass the oldest key through unmapped. It's held
+                        // like a key flushed by a fn_key tap, so it goes up unmapped too
+                        let oldest = self.layers[l].event_buffer.remove(0);
+                        self.raw_held.push(oldest);
+                        let out = self.global_code(oldest, KeyState::DOWN as i32);
+                        send_key_down(self.emitter.as_mut(), out);
+                    }
+                    // add to event buffer
+                    self.layers[l].event_buffer.push(ev.code());
+                }
+                KeyState::UP => {
+                    let code = ev.code();
+                    if ev.kind() == InputEventKind::Key(self.settings.layers[l].fn_key) {
+                        self.stats.taps += 1;
+                        if layer.event_buffer.is_empty() {
+                            let now = Instant::now();
+                            let double_tap = match (self.settings.double_tap, layer.last_tap) {
+                                (Some(window), Some(last)) => now.duration_since(last) <= window,
+                                _ => false,
+                            };
+                            if double_tap {
+                                layer.last_tap = None;
+                                layer.locked = true;
+                                layer.state = State::SHIFT;
+                                return true;
+                            }
+                            layer.last_tap = Some(now);
+                            if !self.settings.sequences.is_empty() {
+                                layer.start_time = now;
+                                layer.state = State::SEQUENCE;
+                                return true;
+                            }
+                            if self.settings.oneshot {
+                                layer.oneshot = true;
+                                layer.state = State::SHIFT;
+                                return true;
+                            }
+                        }
+                        // The fn_key tap and all buffered keys as down, in one write.
+                        // They're still held and go up unmapped whichever layer is active then
+                        let mut keys = self.settings.tap(l);
+                        let buffered = layer.event_buffer.clone();
+                        self.raw_held.extend(&buffered);
+                        for code in buffered {
+                            keys.push((self.global_code(code, KeyState::DOWN as i32), KeyState::DOWN as i32));
+                        }
+                        send_keys(self.emitter.as_mut(), &keys, self.settings.inter_key_delay);
+                        self.syn();
+                        self.enter_idle(l);
+                        return true;
+                    } else if layer.event_buffer.contains(&code) {
+                        // remove ev from buffer
+                        layer.event_buffer.retain(|c| c != &code);
+                        layer.state = State::SHIFT;
+                        self.stats.shifts += 1;
+                        self.press_mapped(l, code);
+                        self.release_mapped(l, code);
+                        self.syn();
+                        return true;
+                    } else {
+                        // Not buffered, so the key went down before fn_key and its DOWN
+                        // already went out unmapped. The UP always goes out unmapped too,
+                        // so the host never sees the key stuck
+                        self.pass_key(code, ev.value());
+                        if self.settings.remap_prefn_release
+                            && self.mapping(l, code).is_some()
+                        {
+                            // Count it as a key tapped inside the hold
+                            self.layers[l].state = State::SHIFT;
+                            self.stats.shifts += 1;
+                            self.press_mapped(l, code);
+                            self.release_mapped(l, code);
+                            self.syn();
+                            return true;
+                        }
+                    }
+                }
+                KeyState::REPEAT => {
+                    // fn_key and buffered keys aren't decided yet, keys pressed
+                    // before fn_key keep repeating
+                    let code = ev.code();
+                    if ev.kind() != InputEventKind::Key(self.settings.layers[l].fn_key)
+                        && !layer.event_buffer.contains(&code)
+                    {
+                        self.pass_key(code, ev.value());
+                    }
+                }
+            }
+        }
+        
+        false
+    }
+    
+    // The buffered keys are still physically held, so press them and leave
+    // them in the buffer for state_shift to release on their real UP
+    fn shift_buffered(&mut self, l: usize) {
+        for code in self.layers[l].event_buffer.clone() {
+            self.press_mapped(l, code);
+        }
+        self.syn();
+        self.layers[l].state = State::SHIFT;
+        self.stats.shifts += 1;
+    }
+    
+    fn state_shift(&mut self, l: usize, ev: InputEvent, key_state: KeyState) -> bool {
+        let is_fn_key = ev.kind() == InputEventKind::Key(self.settings.layers[l].fn_key);
+        if is_fn_key && (self.layers[l].locked || self.layers[l].oneshot) {
+            // Tapping fn_key again unlocks or cancels a one-shot, its press and repeats are swallowed
+            if ev.value() == KeyState::UP as i32 {
+                self.release_buffered(l);
+                self.syn();
+                self.enter_idle(l);
+            }
+            return true;
+        }
+        if is_fn_key && ev.value() == KeyState::UP as i32 {
+            // Send all buffered key events as up
+            self.release_buffered(l);
+            self.syn();
+            self.enter_idle(l);
+            return true;
+        }
+
+        let code = ev.code();
+        if self.settings.passthrough.contains(&code) {
+            self.pass_key(code, ev.value());
+            return false;
+        }
+        let mapped = self.mapping(l, code).is_some();
+        if self.layers[l].oneshot
+            && is_down(ev.value())
+            && (mapped || !is_modifier(code))
+        {
+            // The layer is done, the key's release is matched up in release_oneshot
+            self.press_mapped(l, code);
+            self.syn();
+            self.oneshot_held.push((l, code));
+            self.enter_idle(l);
+            return true;
+        }
+        let condition = self.held_condition(code);
+        let mapping = self.settings.mapping(l, code, &self.key_devices, &self.disabled);
+        if let Some(output) = mapping.map(|m| m.output(condition)) {
+            // The buffer holds held source keys so their whole chord can be released
+            let pending = self.layers[l].pending_holds.iter().position(|(c, _)| *c == code);
+            // Keys with a double_tap send a first tap once they're released
+            let double_tap = self.mapping(l, code).and_then(Mapping::double_tap);
+            let first_tap = double_tap.is_some() && condition != double_tap;
+            match key_state {
+                KeyState::UP if pending.is_some() => {
+                    let (_, down) = self.layers[l].pending_holds.remove(pending.unwrap());
+                    // Held long enough, tick just didn't get to it yet
+                    if self.settings.layers[l].min_hold.is_some_and(|min_hold| down.elapsed() >= min_hold) {
+                        self.press_mapped(l, code);
+                        self.release_mapped(l, code);
+                        self.syn();
+                    } else {
+                        debug!("Dropping {:?}, it wasn't held for min_hold_ms", Key::new(code));
+                    }
+                }
+                KeyState::UP => {
+                    // remove ev from buffer
+                    self.layers[l].event_buffer.retain(|c| c != &code);
+                    self.release_mapped(l, code);
+                    self.syn();
+                }
+                KeyState::DOWN if self.settings.layers[l].min_hold.is_some() => {
+                    // Pressed by tick once it's held long enough
+                    self.layers[l].pending_holds.push((code, Instant::now()));
+                }
+                KeyState::DOWN => {
+                    self.layers[l].event_buffer.push(code);
+                    self.press_mapped(l, code);
+                    self.syn();
+                }
+                // Not pressed yet, so nothing to repeat
+                KeyState::REPEAT if pending.is_some() || first_tap => {}
+                KeyState::REPEAT => {
+                    // Only the main key of a chord auto-repeats, the buffer is unchanged.
+                    // Scrolling repeats on its own timer
+                    if let Output::Chord(chord) = output {
+                        self.send_source_key(code, chord[chord.len() - 1], KeyState::REPEAT as i32);
+                    }
+                }
+            }
+        } else {
+            if ev.value() == KeyState::UP as i32 {
+                // unmapped keys can still be buffered from a DECIDE timeout
+                self.layers[l].event_buffer.retain(|c| c != &code);
+            }
+            self.pass_key(code, ev.value());
+        }
+        
+        false
+    }
+    
+    fn state_sequence(&mut self, l: usize, ev: InputEvent, key_state: KeyState) -> bool {
+        let code = ev.code();
+        if self.layers[l].start_time.elapsed() >= self.settings.sequence_timeout {
+            self.abandon_sequence(l);
+            return self.state_idle(ev, key_state);
+        }
+        if self.layers[l].event_buffer.contains(&code) {
+            // A key of the sequence, only its press counts
+            if key_state == KeyState::UP {
+                self.layers[l].event_buffer.retain(|c| *c != code);
+                if let Some(typed) = self.layers[l].sequence.iter_mut().find(|(c, released)| *c == code && !released) {
+                    typed.1 = true;
+                }
+            }
+            return true;
+        }
+        if key_state != KeyState::DOWN
+            || is_modifier(code)
+            || self.settings.passthrough.contains(&code)
+        {
+            // Releases of keys pressed before, and modifiers held for the keys after the sequence
+            self.pass_key(code, ev.value());
+            return false;
+        }
+        let mut typed: Vec<u16> = self.layers[l].sequence.iter().map(|(c, _)| *c).collect();
+        typed.push(code);
+        if let Some(chord) = self.settings.sequences.get(&typed).cloned() {
+            send_chord_down(self.emitter.as_mut(), &chord, self.settings.inter_key_delay);
+            send_chord_up(self.emitter.as_mut(), &chord, self.settings.inter_key_delay);
+            self.syn();
+            self.stats.keys_remapped += 1;
+            // Keys still held are done as well
+            self.swallowed.extend(self.layers[l].event_buffer.iter().copied());
+            self.swallowed.push(code);
+            self.enter_idle(l);
+            true
+        } else if self.settings.sequences.keys().any(|sequence| sequence.starts_with(&typed)) {
+            let layer = &mut self.layers[l];
+            layer.sequence.push((code, false));
+            layer.event_buffer.push(code);
+            layer.start_time = Instant::now();
+            true
+        } else {
+            self.abandon_sequence(l);
+            self.state_idle(ev, key_state)
+        }
+    }
+    
+    // No sequence matches, send the fn_key tap and the keys typed since as they were
+    fn abandon_sequence(&mut self, l: usize) {
+        let mut keys = self.settings.tap(l);
+        for (code, released) in self.layers[l].sequence.clone() {
+            keys.push((self.global_code(code, KeyState::DOWN as i32), KeyState::DOWN as i32));
+            if released {
+                keys.push((self.global_code(code, KeyState::UP as i32), KeyState::UP as i32));
+            } else {
+                self.raw_held.push(code);
+            }
+        }
+        send_keys(self.emitter.as_mut(), &keys, self.settings.inter_key_delay);
+        self.syn();
+        self.enter_idle(l);
+    }
+    
+    // Non-key events aren't part of any layer and go out unchanged
+    fn forward(&mut self, ev: InputEvent) {
+        if self.settings.emit_scancode && ev.kind() == InputEventKind::Misc(evdev::MiscType::MSC_SCAN) {
+            // Comes right before its key event and goes out along with whatever that's mapped to
+            self.pending_scancode = Some(ev.value());
+            return;
+        }
+        emit_or_log("event", || self.emitter.emit_event(ev));
+        self.emitter.report(Some(ev), self.state().name());
+    }
+    
+    // A key sent for source alone, e.g. passed through or mapped to a single key.
+    // With preserve_timestamps it keeps the time of source's event if that's the one being run
+    fn send_source_key(&mut self, source: u16, code: u16, value: i32) {
+        match self.stamp.filter(|ev| ev.code() == source) {
+            Some(ev) => {
+                let event = stamped(code, value, ev.timestamp());
+                emit_or_log("key", || self.emitter.emit_event(event));
+            }
+            None => send_key_i32(self.emitter.as_mut(), code, value),
+        }
+    }
+    
+    fn note_key_down(&mut self, now: Instant) {
+        if let Some(last) = self.last_key_down.replace(now) {
+            if self.key_intervals.len() == ADAPTIVE_SAMPLES {
+                self.key_intervals.pop_front();
+            }
+            self.key_intervals.push_back(now.duration_since(last).min(ADAPTIVE_MAX_INTERVAL));
+        }
+    }
+    
+    // mode_switch_timeout, or with adaptive_timeout_ms a multiple of the recent time between
+    // presses: short while typing fast, long while pressing the odd arrow key
+    fn effective_timeout(&self) -> Duration {
+        let Some((min, max)) = self.settings.adaptive_timeout else { return self.settings.timeout };
+        if self.key_intervals.is_empty() {
+            return self.settings.timeout.clamp(min, max);
+        }
+        let average = self.key_intervals.iter().sum::<Duration>() / self.key_intervals.len() as u32;
+        (average * ADAPTIVE_TIMEOUT_FACTOR).clamp(min, max)
+    }
+    
+    // escape_combo was held long enough, time to let go of everything and exit
+    fn escaped(&self) -> bool {
+        self.escape_since
+            .zip(self.settings.escape_hold)
+            .is_some_and(|(since, hold)| since.elapsed() >= hold)
+    }
+    
+    // A key going out unmapped, as whatever global_keymap swaps it for
+    fn pass_key(&mut self, code: u16, value: i32) {
+        let out = self.global_code(code, value);
+        self.send_source_key(code, out, value);
+    }
+    
+    // What an unmapped key goes out as. Presses look it up in global_keymap, unless paused,
+    // repeats and releases go out as whatever the press did
+    fn global_code(&mut self, code: u16, value: i32) -> u16 {
+        let held = self.global_held.iter().position(|(c, _)| *c == code);
+        match (held, KeyState::try_from(value)) {
+            (_, Ok(KeyState::DOWN)) => {
+                if let Some(i) = held {
+                    self.global_held.remove(i);
+                }
+                let out = self.settings.global_keymap.get(&code).copied().filter(|_| !self.paused);
+                if let Some(out) = out {
+                    self.global_held.push((code, out));
+                }
+                out.unwrap_or(code)
+            }
+            (Some(i), Ok(KeyState::UP)) => self.global_held.remove(i).1,
+            (Some(i), _) => self.global_held[i].1,
+            (None, _) => code,
+        }
+    }
+    
+    // Release mapped keys and anything else that went out, e.g. keys typed in IDLE
+    fn release_all(&mut self) {
+        self.release_layers();
+        let pressed = self.emitter.pressed();
+        send_chord_up(self.emitter.as_mut(), &pressed, self.settings.inter_key_delay);
+        self.raw_held.clear();
+        self.global_held.clear();
+        self.caps_word_held.clear();
+        self.pending_tap = None;
+        self.syn();
+    }
+    
+    fn toggle_paused(&mut self) {
+        self.paused = !self.paused;
+        if self.paused {
+            // Nothing may stay down while tokey is paused
+            self.release_all();
+        }
+        info!("{}", if self.paused { "Paused" } else { "Resumed" });
+        if let Some(path) = &self.paused_file {
+            if let Err(e) = std::fs::write(path, format!("{}\n", self.paused)) {
+                warn!("Can't save paused state to {}: {}", path.display(), e);
+            }
+        }
+        if let Some(messenger) = &self.messenger {
+            messenger.set_paused(self.paused);
+        }
+    }
+}
+
+// Missing or unreadable state counts as not paused
+fn read_paused(path: &Path) -> bool {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents.trim().parse().unwrap_or_else(|_| {
+            warn!("Ignoring corrupt paused state in {}", path.display());
+            false
+        }),
+        Err(_) => false,
+    }
+}
+
+fn paused_file_path() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("tokey")
+        .ok()?
+        .place_data_file(PAUSED_FILE_NAME)
+        .map_err(|e| warn!("Can't create data directory, paused state won't be saved: {}", e))
+        .ok()
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: nix::libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_shutdown(_: nix::libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn install_signal_handlers() -> nix::Result<()> {
+    let sighup_action = signal::SigAction::new(
+        signal::SigHandler::Handler(handle_sighup),
+        signal::SaFlags::empty(),
+        signal::SigSet::empty(),
+    );
+    let shutdown_action = signal::SigAction::new(
+        signal::SigHandler::Handler(handle_shutdown),
+        signal::SaFlags::empty(),
+        signal::SigSet::empty(),
+    );
+    unsafe {
+        signal::sigaction(signal::Signal::SIGHUP, &sighup_action)?;
+        signal::sigaction(signal::Signal::SIGTERM, &shutdown_action)?;
+        signal::sigaction(signal::Signal::SIGINT, &shutdown_action)?;
+    }
+    Ok(())
+}
+
+// Run recorded events with their original timing, so timeouts and scrolling behave as they did
+fn replay(path: &Path, state_machine: &mut StateMachine) -> Result<(), String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let events = replay::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let start = Instant::now();
+    for (at, ev) in events {
+        let due = start + at;
+        loop {
+            let now = Instant::now();
+            if now >= due {
+                break;
+            }
+            let wait = match state_machine.poll_timeout() {
+                -1 => due - now,
+                ms => Duration::from_millis(ms as u64).min(due - now),
+            };
+            std::thread::sleep(wait);
+            state_machine.tick();
+        }
+        match ev.event_type() {
+            evdev::EventType::KEY if ev.code() != 0 => {
+                info!("read {:?} {}", Key::new(ev.code()), ev.value());
+                state_machine.run(ev);
+            }
+            evdev::EventType::KEY | evdev::EventType::SYNCHRONIZATION => {}
+            _ => {
+                info!("read {:?}", ev);
+                state_machine.forward(ev);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn reload_config(state_machine: &mut StateMachine, conf_path: &Path) {
+    if conf_path == Path::new(STDIN_CONF_PATH) {
+        error!("Keeping previous config: it was read from stdin, which can't be read again");
+        return;
+    }
+    // Swapped in place except for what's only read on startup: device_name, virtual_device_name,
+    // virtual_autorepeat, startup_grace_ms, reconnect, handle_suspend, watchdog_ms, log_file,
+    // emit_scancode and buttons or axes mapped for the first time, which the virtual device lacks
+    match read_config(conf_path).and_then(|config| state_machine.reload(config)) {
+        Ok(()) => info!("Reloaded config from {}", conf_path.display()),
+        Err(e) => error!("Keeping previous config: {}", e),
+    }
+}
+
+fn reload_keymap(state_machine: &mut StateMachine, conf_path: &Path) {
+    if conf_path == Path::new(STDIN_CONF_PATH) {
+        error!("Keeping previous keymap: the config was read from stdin, which can't be read again");
+        return;
+    }
+    match read_config(conf_path).and_then(|config| state_machine.reload_keymap(config)) {
+        Ok(()) => info!("Reloaded keymap from {}", conf_path.display()),
+        Err(e) => error!("Keeping previous keymap: {}", e),
+    }
+}
+
+// Let go of the keyboards or take them back, returns whether they're grabbed now.
+// Keys tokey pressed are released first, since their real release won't reach it
+fn set_grabbed(
+    devices: &mut [evdev::Device],
+    state_machine: &mut StateMachine,
+    grabbed: bool,
+    grace: Duration,
+) -> bool {
+    if grabbed {
+        // Same as on startup, keys held now would otherwise stay down for the system
+        wait_for_key_release(devices, grace);
+        for i in 0..devices.len() {
+            if let Err(e) = grab(&mut devices[i]) {
+                error!("Staying ungrabbed: {}", e);
+                for dev in devices[..i].iter_mut() {
+                    let _ = dev.ungrab();
+                }
+                return false;
+            }
+        }
+        discard_pending_events(devices);
+        state_machine.reset();
+        info!("Grabbed");
+    } else {
+        state_machine.release_all();
+        state_machine.reset();
+        for dev in devices.iter_mut() {
+            if let Err(e) = dev.ungrab() {
+                error!("Can't ungrab {}: {}", dev.name().unwrap_or("<unnamed>"), e);
+            }
+        }
+        info!("Ungrabbed");
+    }
+    if let Some(messenger) = &state_machine.messenger {
+        messenger.set_grabbed(grabbed);
+    }
+    grabbed
+}
+
+// Run the commands that came in over dbus or from a watcher, returns whether the devices have
+// to be opened again
+fn handle_commands(
+    commands: &command::CommandReceiver,
+    state_machine: &mut StateMachine,
+    devices: &mut [evdev::Device],
+    grabbed: &mut bool,
+    conf_path: &Path,
+    monitor: bool,
+    grace: Duration,
+) -> bool {
+    let mut reopen = false;
+    for command in commands.drain() {
+        match command {
+            Command::Reload => reload_config(state_machine, conf_path),
+            Command::ReloadKeymap => reload_keymap(state_machine, conf_path),
+            Command::TogglePause => state_machine.toggle_paused(),
+            Command::SetPaused(paused) => {
+                if paused != state_machine.paused {
+                    state_machine.toggle_paused();
+                }
+            }
+            Command::SetMappingEnabled(code, enabled) => state_machine.set_mapping_enabled(code, enabled),
+            Command::SetGrabbed(grab) if monitor => {
+                let method = if grab { "Grab" } else { "Ungrab" };
+                info!("Ignoring {}, nothing is grabbed with --monitor", method)
+            }
+            Command::SetGrabbed(grab) if grab != *grabbed => {
+                *grabbed = set_grabbed(devices, state_machine, grab, grace);
+            }
+            Command::SetGrabbed(_) => {}
+            Command::Resumed => {
+                // The devices may be new nodes now, with the old fds never saying so. Keys
+                // held going to sleep won't have their release read from them
+                info!("Resumed from sleep, opening the devices again");
+                state_machine.release_all();
+                state_machine.reset();
+                reopen = true;
+            }
+            #[cfg(feature = "x11_profiles")]
+            Command::ActiveWindow(class) => state_machine.set_window_class(class),
+        }
+    }
+    reopen
+}
+
+// Keys held when tokey starts (usually the Enter that launched it) have to be released
+// before grabbing, otherwise their UP never reaches the rest of the system
+fn wait_for_key_release(devices: &[evdev::Device], grace: Duration) {
+    let deadline = Instant::now() + grace;
+    loop {
+        let held: Vec<Key> = devices
+            .iter()
+            .filter_map(|dev| dev.get_key_state().ok())
+            .flat_map(|keys| keys.iter().collect::<Vec<_>>())
+            .collect();
+        if held.is_empty() {
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!("Keys still held after startup_grace_ms, grabbing anyway: {:?}", held);
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Throw away whatever was queued before the grab, it already reached the system
+fn discard_pending_events(devices: &mut [evdev::Device]) {
+    for dev in devices.iter_mut() {
+        while let Ok(events) = dev.fetch_events() {
+            if events.count() == 0 {
+                break;
+            }
+        }
+    }
+}
+
+// A virtual keyboard declaring everything the devices can send plus `extra`
+fn build_virtual_device(
+    name: &str,
+    devices: &[evdev::Device],
+    extra: &ExtraOutputs,
+    auto_repeat: bool,
+) -> std::io::Result<VirtualDevice> {
+    let mut keys = evdev::AttributeSet::<Key>::new();
+    // Non-key events the devices send are passed through, so declare those too
+    let mut axes = evdev::AttributeSet::<RelativeAxisType>::new();
+    let mut misc = evdev::AttributeSet::<evdev::MiscType>::new();
+    let mut leds = evdev::AttributeSet::<evdev::LedType>::new();
+    for dev in devices {
+        if let Some(supported) = dev.supported_keys() {
+            for key in supported.iter() {
+                keys.insert(key);
+            }
+        }
+        if let Some(supported) = dev.supported_relative_axes() {
+            for axis in supported.iter() {
+                axes.insert(axis);
+            }
+        }
+        if let Some(supported) = dev.misc_properties() {
+            for m in supported.iter() {
+                misc.insert(m);
+            }
+        }
+        if let Some(supported) = dev.supported_leds() {
+            for led in supported.iter() {
+                leds.insert(led);
+            }
+        }
+    }
+    if keys.iter().next().is_none() {
+        warn!("None of the devices report their keys, declaring a standard keyboard's");
+        declare_default_keys(&mut keys);
+    }
+    for key in &extra.buttons {
+        keys.insert(*key);
+    }
+    for axis in &extra.rel_axes {
+        axes.insert(*axis);
+    }
+    let mut builder = uinput::VirtualDeviceBuilder::new()?
+        .name(name)
+        .with_keys(&keys)?;
+    if axes.iter().next().is_some() {
+        builder = builder.with_relative_axes(&axes)?;
+    }
+    if misc.iter().next().is_some() {
+        builder = builder.with_misc(&misc)?;
+    }
+    if leds.iter().next().is_some() {
+        builder = builder.with_leds(&leds)?;
+    }
+    if auto_repeat {
+        builder = builder.with_auto_repeat()?;
+    }
+    builder.build()
+}
+
+// Every keycode of a regular keyboard, for devices that don't say which keys they have
+fn declare_default_keys(keys: &mut evdev::AttributeSet<Key>) {
+    for code in 1..=Key::KEY_MICMUTE.code() {
+        keys.insert(Key::new(code));
+    }
+}
+
+// Everything connect sets up, replaced as a whole on reconnect
+struct Connection {
+    devices: Vec<evdev::Device>,
+    emitter: Box<dyn KeyEmitter>,
+    // LED changes the host makes on the virtual device, None in monitor mode
+    feedback: Option<uinput::Feedback>,
+    // The virtual device repeats held keys itself, so repeats from the devices are dropped
+    auto_repeat: bool,
+}
+
+// Open and grab the configured devices along with a virtual keyboard that can emit all their keys
+// plus `extra`. In monitor mode nothing is grabbed and output only goes to the log.
+// Without `virtual_autorepeat` the virtual keyboard only repeats what the devices send
+fn connect(
+    device_name: &toml::Value,
+    virt_dev_name: &str,
+    extra: &ExtraOutputs,
+    grace: Duration,
+    monitor: bool,
+    virtual_autorepeat: bool,
+) -> Result<Connection, DeviceError> {
+    let mut devices = get_devices(device_name)?;
+    if monitor {
+        return Ok(Connection {
+            devices,
+            emitter: monitor_emitter(false),
+            feedback: None,
+            auto_repeat: false,
+        });
+    }
+    let auto_repeat = virtual_autorepeat
+        && devices.iter().any(|dev| dev.supported_events().contains(evdev::EventType::REPEAT));
+    let virt_dev = build_virtual_device(virt_dev_name, &devices, extra, auto_repeat)
+        .map_err(DeviceError::Uinput)?;
+    let feedback = virt_dev.feedback().map_err(DeviceError::Uinput)?;
+    
+    // Only grab once the virtual device exists, so a failure above leaves the keyboard usable
+    wait_for_key_release(&devices, grace);
+    for i in 0..devices.len() {
+        if let Err(e) = grab(&mut devices[i]) {
+            // Don't leave the ones before it grabbed with nothing reading them
+            for dev in devices[..i].iter_mut() {
+                let _ = dev.ungrab();
+            }
+            return Err(e);
+        }
+    }
+    discard_pending_events(&mut devices);
+    Ok(Connection {
+        devices,
+        emitter: Box::new(virt_dev),
+        feedback: Some(feedback),
+        auto_repeat,
+    })
+}
+
+// Retries while the device is busy, other errors won't go away by waiting
+fn grab(dev: &mut evdev::Device) -> Result<(), DeviceError> {
+    let mut delay = GRAB_RETRY_DELAY;
+    for attempt in 1.. {
+        match dev.grab() {
+            Ok(()) => break,
+            Err(e) if attempt < GRAB_ATTEMPTS && e.raw_os_error() == Some(nix::libc::EBUSY) => {
+                warn!("{} is busy, retrying in {:?}", dev.name().unwrap_or("<unnamed>"), delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => return Err(DeviceError::Grab(dev.name().unwrap_or("<unnamed>").to_string(), e)),
+        }
+    }
+    Ok(())
+}
+
+// Epoll handle watching the devices, the virtual device and the command channel
+fn create_event_epoll(
+    devices: &[evdev::Device],
+    feedback: Option<&uinput::Feedback>,
+    commands_fd: RawFd,
+) -> nix::Result<RawFd> {
+    let epoll_fd = create_epoll(devices)?;
+    epoll_add(epoll_fd, commands_fd, COMMANDS_EPOLL_TAG)?;
+    if let Some(feedback) = feedback {
+        epoll_add(epoll_fd, feedback.fd(), FEEDBACK_EPOLL_TAG)?;
+    }
+    Ok(epoll_fd)
+}
+
+// Show the host's LED state (Caps Lock etc.) on the real keyboards
+fn relay_leds(feedback: &mut uinput::Feedback, devices: &mut [evdev::Device]) {
+    let leds = match feedback.fetch_leds() {
+        Ok(leds) => leds,
+        Err(e) => {
+            warn!("Can't read LED state from the virtual device: {}", e);
+            return;
+        }
+    };
+    if leds.is_empty() {
+        return;
+    }
+    for dev in devices.iter_mut() {
+        if let Err(e) = dev.send_events(&leds) {
+            debug!("Can't set LEDs on {}: {}", dev.name().unwrap_or("<unnamed>"), e);
+        }
+    }
+}
+
+// Poll until every configured device is back, None if shutdown was requested meanwhile
+fn reconnect(
+    device_name: &toml::Value,
+    virt_dev_name: &str,
+    extra: &ExtraOutputs,
+    grace: Duration,
+    monitor: bool,
+    virtual_autorepeat: bool,
+) -> Option<Connection> {
+    loop {
+        std::thread::sleep(RECONNECT_INTERVAL);
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            return None;
+        }
+        match connect(device_name, virt_dev_name, extra, grace, monitor, virtual_autorepeat) {
+            Ok(connected) => return Some(connected),
+            Err(e) => debug!("Still waiting for device: {}", e),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // setup
+    logging::init();
+    let args = get_args();
+    let (config, conf_path) = get_config(&args);
+    let virt_dev_name = config
+        .virtual_device_name
+        .clone()
+        .unwrap_or_else(|| DEFAULT_VIRTUAL_DEVICE_NAME.to_string());
+    if virt_dev_name.len() > MAX_VIRTUAL_DEVICE_NAME_LEN {
+        eprintln!(
+            "virtual_device_name can be at most {} bytes long",
+            MAX_VIRTUAL_DEVICE_NAME_LEN
+        );
+        exit(1);
+    }
+    let startup_grace = Duration::from_millis(
+        config.startup_grace_ms.unwrap_or(DEFAULT_STARTUP_GRACE_MS)
+    );
+    let reconnect_enabled = config.reconnect.unwrap_or(true);
+    let handle_suspend = config.handle_suspend.unwrap_or(false);
+    let virtual_autorepeat = config.virtual_autorepeat.unwrap_or(true);
+    let watchdog = config
+        .watchdog_ms
+        .filter(|ms| *ms > 0)
+        .map(|ms| watchdog::spawn(Duration::from_millis(ms)));
+    let device_name = config.device_name.clone();
+    
+    let monitor = args.monitor;
+    if monitor {
+        // State transitions are logged at debug level
+        logging::raise_level(logging::Level::Debug);
+    }
+    
+    let settings = Settings::from_config(config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    
+    if let Some(path) = &args.replay {
+        // Same output as --monitor, nothing is grabbed or emitted
+        logging::raise_level(logging::Level::Debug);
+        let mut state_machine = StateMachine::new(
+            monitor_emitter(args.json),
+            settings,
+            None,
+            None,
+        );
+        if let Err(e) = replay(path, &mut state_machine) {
+            eprintln!("{}", e);
+            exit(1);
+        }
+        exit(0);
+    }
+    
+    // Nothing is written in monitor mode
+    let uinput = Some(Path::new("/dev/uinput")).filter(|_| !monitor);
+    let problems = match configured_device_paths(&device_name) {
+        Some(paths) => permission_problems(&paths, false, uinput),
+        None => permission_problems(&event_device_paths().unwrap_or_default(), true, uinput),
+    };
+    if !problems.is_empty() {
+        warn!("{}", permission_advice(&problems));
+    }
+    
+    // Grab and Ungrab over dbus hand the keyboards to the system and back
+    let mut grabbed = true;
+    let Connection { mut devices, mut emitter, mut feedback, mut auto_repeat } = connect(
+        &device_name,
+        &virt_dev_name,
+        &settings.extra_outputs(),
+        startup_grace,
+        monitor,
+        virtual_autorepeat,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    
+    if args.json {
+        emitter = monitor_emitter(true);
+    }
+    let missing = settings.missing_fn_keys(|key| {
+        devices.iter().any(|dev| dev.supported_keys().is_some_and(|keys| keys.contains(key)))
+    });
+    if !missing.is_empty() {
+        warn!(
+            "No grabbed device has the fn_key {}, add the device with it (e.g. the mouse) to device_name",
+            missing.join(", ")
+        );
+    }
+    
+    let (command_tx, commands) = command::channel()?;
+    #[cfg(feature = "x11_profiles")]
+    x11_profiles::watch(command_tx.clone());
+    if handle_suspend {
+        tokey_ipc::watch_sleep(command_tx.clone());
+    }
+    let mut state_machine = StateMachine::new(
+        emitter,
+        settings,
+        paused_file_path().filter(|_| !monitor),
+        Some(tokey_ipc::Messenger::new(command_tx))
+    );
+    
+    install_signal_handlers()?;
+    // The keyboard is grabbed and the virtual device is up
+    #[cfg(feature = "systemd")]
+    systemd::notify("READY=1");
+    
+    let mut epoll_fd = create_event_epoll(&devices, feedback.as_ref(), commands.fd())?;
+    let mut epoll_events = vec![epoll::EpollEvent::empty(); devices.len() + 2];
+    'event_loop: loop {
+        let timeout = state_machine.poll_timeout();
+        if let Some(watchdog) = &watchdog {
+            watchdog.waiting();
+        }
+        let ready = match epoll::epoll_wait(epoll_fd, &mut epoll_events, timeout) {
+            Ok(n) => n,
+            Err(nix::errno::Errno::EINTR) => 0,
+            Err(e) => {
+                error!("{}", e);
+                break;
+            }
+        };
+        if let Some(watchdog) = &watchdog {
+            watchdog.busy();
+        }
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            break;
+        }
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            reload_config(&mut state_machine, &conf_path);
+        }
+        state_machine.tick();
+        if state_machine.escaped() {
+            warn!("escape_combo held, releasing the keyboard and exiting");
+            state_machine.release_all();
+            break;
+        }
+        let mut unplugged = false;
+        for epoll_event in &epoll_events[..ready] {
+            if epoll_event.data() == COMMANDS_EPOLL_TAG {
+                unplugged |= handle_commands(
+                    &commands,
+                    &mut state_machine,
+                    &mut devices,
+                    &mut grabbed,
+                    &conf_path,
+                    monitor,
+                    startup_grace,
+                );
+                continue;
+            }
+            if epoll_event.data() == FEEDBACK_EPOLL_TAG {
+                if let Some(feedback) = feedback.as_mut() {
+                    relay_leds(feedback, &mut devices);
+                }
+                continue;
+            }
+            let dev = &mut devices[epoll_event.data() as usize];
+            state_machine.set_source(dev.name());
+            match dev.fetch_events() {
+                // The system gets these on its own while ungrabbed
+                Ok(_) if !grabbed => {}
+                Ok(iterator) => {
+                    for ev in iterator {
+                        if ev.event_type() != evdev::EventType::KEY {
+                            // SYN_REPORTs are dropped, every emit already ends with one
+                            if ev.event_type() != evdev::EventType::SYNCHRONIZATION {
+                                if monitor {
+                                    info!("read {:?}", ev);
+                                }
+                                state_machine.forward(ev);
+                            }
+                            continue;
+                        }
+                        // With auto_repeat the virtual device repeats held keys on its own
+                        if ev.code() == 0 || (auto_repeat && ev.value() == KeyState::REPEAT as i32) {
+                            continue;
+                        }
+                        
+                        if monitor {
+                            info!("read {:?} {}", Key::new(ev.code()), ev.value());
+                        }
+                        if state_machine.run(ev) {
+                            break;
+                        }
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) if reconnect_enabled && e.raw_os_error() == Some(nix::libc::ENODEV) => {
+                    warn!("Input device disappeared, waiting for it to come back");
+                    unplugged = true;
+                    break;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    break 'event_loop;
+                }
+            }
+        }
+        
+        if unplugged {
+            // Let go of the remaining devices so they stay usable until everything is back
+            for dev in devices.iter_mut() {
+                let _ = dev.ungrab();
+            }
+            devices.clear();
+            nix::unistd::close(epoll_fd)?;
+            // Waiting for the device isn't being stuck
+            if let Some(watchdog) = &watchdog {
+                watchdog.waiting();
+            }
+            let extra = state_machine.settings.extra_outputs();
+            let connection = match reconnect(
+                &device_name,
+                &virt_dev_name,
+                &extra,
+                startup_grace,
+                monitor,
+                virtual_autorepeat,
+            ) {
+                Some(connected) => connected,
+                None => {
+                    #[cfg(feature = "systemd")]
+                    systemd::notify("STOPPING=1");
+                    return Ok(());
+                }
+            };
+            info!("Reconnected");
+            devices = connection.devices;
+            // Reconnecting grabs them again
+            if !grabbed {
+                grabbed = true;
+                if let Some(messenger) = &state_machine.messenger {
+                    messenger.set_grabbed(true);
+                }
+            }
+            feedback = connection.feedback;
+            auto_repeat = connection.auto_repeat;
+            state_machine.set_emitter(match args.json {
+                true => monitor_emitter(true),
+                false => connection.emitter,
+            });
+            epoll_fd = create_event_epoll(&devices, feedback.as_ref(), commands.fd())?;
+        }
+    }
+
+    #[cfg(feature = "systemd")]
+    systemd::notify("STOPPING=1");
+    info!("{}", state_machine.stats);
+    // Release every device even if one fails so the keyboard is never left grabbed
+    for dev in devices.iter_mut().filter(|_| !monitor) {
+        if let Err(e) = dev.ungrab() {
+            error!("Can't ungrab {}: {}", dev.name().unwrap_or("<unnamed>"), e);
+        }
+    }
+    nix::unistd::close(epoll_fd)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Records every emitted (code, value) pair and, separately, every non-key event
+    // as (type, code, value), shared so tests can inspect them
+    #[derive(Clone, Default)]
+    struct RecordingEmitter(Rc<RefCell<Vec<(u16, i32)>>>, Rc<RefCell<Vec<(u16, u16, i32)>>>);
+
+    impl KeyEmitter for RecordingEmitter {
+        fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+            self.0.borrow_mut().push((code, value));
+            Ok(())
+        }
+
+        fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+            self.1.borrow_mut().push((event.event_type().0, event.code(), event.value()));
+            Ok(())
+        }
+    }
+
+    fn test_config(extra: &str) -> Config {
+        toml::from_str(&format!(
+            r#"device_name = ""
+mode_switch_timeout = 200
+fn_key = "KEY_SPACE"
+pause_key = "KEY_RIGHTALT"
+{}
+[keymap]
+KEY_J = "KEY_LEFT"
+KEY_L = "KEY_RIGHT"
+"#,
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn state_machine(config: Config) -> (StateMachine, RecordingEmitter) {
+        let emitter = RecordingEmitter::default();
+        let sm = StateMachine::new(
+            Box::new(emitter.clone()),
+            Settings::from_config(config).unwrap(),
+            None,
+            None,
+        );
+        (sm, emitter)
+    }
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(evdev::EventType::KEY, key.code(), value)
+    }
+
+    #[test]
+    fn decide_buffer_has_no_spurious_events() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        for k in [Key::KEY_A, Key::KEY_S, Key::KEY_D] {
+            sm.run(key(k, 1));
+        }
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        let emitted = emitter.0.borrow();
+        assert!(emitted.iter().all(|(code, _)| *code != 0));
+        assert_eq!(emitted[0], (Key::KEY_SPACE.code(), 1));
+        assert_eq!(emitted[1], (Key::KEY_SPACE.code(), 0));
+    }
+
+    #[test]
+    fn decide_buffer_overflow_passes_oldest_through() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        let keys = [
+            Key::KEY_1, Key::KEY_2, Key::KEY_3, Key::KEY_4, Key::KEY_5, Key::KEY_6,
+            Key::KEY_7, Key::KEY_8, Key::KEY_9, Key::KEY_0, Key::KEY_A,
+        ];
+        for k in keys {
+            sm.run(key(k, 1));
+        }
+
+        assert_eq!(sm.layers[0].event_buffer.len(), DEFAULT_EVENT_BUFFER_SIZE);
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_1.code(), 1)]);
+
+        let (mut sm, emitter) = state_machine(test_config("event_buffer_size = 2"));
+        sm.run(key(Key::KEY_SPACE, 1));
+        for k in [Key::KEY_1, Key::KEY_2, Key::KEY_3, Key::KEY_4] {
+            sm.run(key(k, 1));
+        }
+        assert_eq!(sm.layers[0].event_buffer, vec![Key::KEY_3.code(), Key::KEY_4.code()]);
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_1.code(), 1), (Key::KEY_2.code(), 1)]);
+        assert!(Settings::from_config(test_config("event_buffer_size = 0")).is_err());
+
+        // The overflowed key goes up unmapped after the layer shifted, as global_keymap swapped it
+        let config = test_config("event_buffer_size = 1\n[global_keymap]\nKEY_J = \"KEY_K\"");
+        let (mut sm, emitter) = state_machine(config);
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_L, 1));
+        sm.run(key(Key::KEY_L, 0));
+        assert!(sm.state() == State::SHIFT);
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_K.code(), 1),
+                (Key::KEY_RIGHT.code(), 1),
+                (Key::KEY_RIGHT.code(), 0),
+                (Key::KEY_K.code(), 0),
+            ]
+        );
+        assert!(sm.emitter.pressed().is_empty());
+        assert!(sm.held_modifiers.is_empty());
+        assert!(sm.layers[0].event_buffer.is_empty());
+        assert!(sm.state() == State::IDLE);
+    }
+
+    #[test]
+    fn decide_timeout_holds_buffered_key_until_release() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.settings.timeout = Duration::from_millis(20);
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        std::thread::sleep(Duration::from_millis(30));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::KEY_LEFT.code(), 1), (Key::KEY_LEFT.code(), 0)]
+        );
+    }
+
+    #[test]
+    fn per_key_timeout_overrides_global_timeout() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.settings.timeout = Duration::from_millis(20);
+        sm.settings.layers[0].keymap.get_mut(&Key::KEY_J.code()).unwrap().timeout =
+            Some(Duration::from_secs(60));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        std::thread::sleep(Duration::from_millis(30));
+        sm.run(key(Key::KEY_K, 1));
+
+        // Still deciding because KEY_J allows a longer hold
+        assert!(matches!(sm.layers[0].state, State::DECIDE));
+        assert!(emitter.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn layers_keep_separate_keymaps() {
+        let (mut sm, emitter) = state_machine(test_config(
+            r#"[layers.sym]
+fn_key = "KEY_CAPSLOCK"
+[layers.sym.keymap]
+KEY_J = "KEY_1"
+"#,
+        ));
+        sm.settings.timeout = Duration::ZERO;
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_1.code(), 1),
+                (Key::KEY_1.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+            ]
+        );
+        assert!(sm.active.is_empty());
+    }
+
+    #[test]
+    fn keymap_errors_list_every_bad_entry() {
+        let keymap: toml::value::Table = toml::from_str(
+            r#"KEY_J = "KEY_LEFT"
+KEY_NOPE = "KEY_RIGHT"
+KEY_K = "KEY_LEFTCTRL+KEY_BAD"
+"#,
+        )
+        .unwrap();
+        let err = get_keymap(keymap, &layout::US, &Aliases::new()).err().unwrap();
+
+        assert!(err.contains("KEY_NOPE = \"KEY_RIGHT\": unknown key \"KEY_NOPE\""));
+        assert!(err.contains("KEY_K = \"KEY_LEFTCTRL+KEY_BAD\": unknown key \"KEY_BAD\""));
+        assert!(!err.contains("KEY_J"));
+    }
+
+    #[test]
+    fn keymap_conflicts_with_fn_and_pause_keys() {
+        let config = test_config(
+            r#"keymap_conflicts = "error"
+[layers.sym]
+fn_key = "KEY_CAPSLOCK"
+[layers.sym.keymap]
+KEY_SPACE = "KEY_1"
+KEY_RIGHTALT = "KEY_2"
+"#,
+        );
+        let err = Settings::from_config(config).err().unwrap();
+
+        assert!(err.contains("Layer sym maps KEY_SPACE, which is the fn_key of layer default"));
+        assert!(err.contains("Layer sym maps KEY_RIGHTALT, which is the pause_key"));
+    }
+
+    #[test]
+    fn real_modifier_is_down_before_mapped_key() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_LEFTSHIFT, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        sm.run(key(Key::KEY_LEFTSHIFT, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFTSHIFT.code(), 1),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_LEFTSHIFT.code(), 0),
+            ]
+        );
+        assert!(sm.held_modifiers.is_empty());
+    }
+
+    #[test]
+    fn fn_tap_emits_fn_key() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::KEY_SPACE.code(), 1), (Key::KEY_SPACE.code(), 0)]
+        );
+        assert!(matches!(sm.state(), State::IDLE));
+    }
+
+    #[test]
+    fn key_tapped_inside_fn_hold_is_mapped() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        assert!(emitter.0.borrow().is_empty());
+        sm.run(key(Key::KEY_J, 0));
+        assert!(matches!(sm.state(), State::SHIFT));
+        // Held again in SHIFT, its repeats go out mapped
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 2));
+        assert_eq!(emitter.0.borrow().last(), Some(&(Key::KEY_LEFT.code(), 2)));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 2),
+                (Key::KEY_LEFT.code(), 0),
+            ]
+        );
+        assert!(matches!(sm.state(), State::IDLE));
+    }
+
+    #[test]
+    fn fn_hold_past_timeout_maps_next_key_right_away() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.settings.timeout = Duration::from_millis(10);
+        sm.run(key(Key::KEY_SPACE, 1));
+        std::thread::sleep(Duration::from_millis(20));
+        sm.run(key(Key::KEY_L, 1));
+
+        assert!(matches!(sm.state(), State::SHIFT));
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_RIGHT.code(), 1)]);
+    }
+
+    #[test]
+    fn unmapped_key_passes_through_in_shift() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.settings.timeout = Duration::ZERO;
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_A, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::KEY_A.code(), 1), (Key::KEY_A.code(), 0)]
+        );
+    }
+
+    #[test]
+    fn double_tap_locks_and_single_tap_unlocks() {
+        let (mut sm, emitter) = state_machine(test_config("double_tap_ms = 10000"));
+        for _ in 0..2 {
+            sm.run(key(Key::KEY_SPACE, 1));
+            sm.run(key(Key::KEY_SPACE, 0));
+        }
+        assert!(matches!(sm.state(), State::SHIFT));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert!(matches!(sm.state(), State::IDLE));
+        sm.run(key(Key::KEY_J, 1));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_J.code(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_mapped_to_mouse_button_emits_click() {
+        let (mut sm, emitter) = state_machine(test_config(
+            r#"[layers.mouse]
+fn_key = "KEY_CAPSLOCK"
+[layers.mouse.keymap]
+KEY_N = "BTN_LEFT"
+KEY_M = "KEY_LEFTCTRL+BTN_RIGHT"
+"#,
+        ));
+        assert_eq!(sm.settings.extra_outputs().buttons, vec![Key::BTN_LEFT, Key::BTN_RIGHT]);
+        sm.settings.timeout = Duration::ZERO;
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_N, 1));
+        sm.run(key(Key::KEY_N, 0));
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::BTN_LEFT.code(), 1), (Key::BTN_LEFT.code(), 0)]
+        );
+    }
+
+    #[test]
+    fn held_scroll_key_repeats_until_release() {
+        let (mut sm, emitter) = state_machine(test_config(
+            r#"scroll_interval_ms = 10
+[layers.nav]
+fn_key = "KEY_CAPSLOCK"
+[layers.nav.keymap]
+KEY_I = "REL_WHEEL:+1"
+KEY_K = "REL_WHEEL:-1"
+"#,
+        ));
+        assert_eq!(sm.settings.extra_outputs().rel_axes, vec![RelativeAxisType::REL_WHEEL]);
+        sm.settings.timeout = Duration::ZERO;
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_I, 1));
+        assert!(sm.poll_timeout() >= 0);
+        std::thread::sleep(Duration::from_millis(15));
+        sm.tick();
+        sm.run(key(Key::KEY_I, 0));
+        assert_eq!(sm.poll_timeout(), -1);
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+
+        let wheel = (evdev::EventType::RELATIVE.0, RelativeAxisType::REL_WHEEL.0, 1);
+        assert_eq!(*emitter.1.borrow(), vec![wheel, wheel]);
+        assert!(emitter.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn non_key_events_are_forwarded_unchanged() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.forward(InputEvent::new(evdev::EventType::MISC, evdev::MiscType::MSC_SCAN.0, 0x70004));
+
+        assert_eq!(
+            *emitter.1.borrow(),
+            vec![(evdev::EventType::MISC.0, evdev::MiscType::MSC_SCAN.0, 0x70004)]
+        );
+        assert!(matches!(sm.state(), State::DECIDE));
+    }
+
+    #[test]
+    fn key_mapped_to_text_types_it_on_press() {
+        let (mut sm, emitter) = state_machine(test_config(
+            r#"[layers.text]
+fn_key = "KEY_CAPSLOCK"
+[layers.text.keymap]
+KEY_A = { text = "=>" }
+"#,
+        ));
+        sm.settings.timeout = Duration::ZERO;
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_A, 1));
+        let typed = emitter.0.borrow().len();
+        sm.run(key(Key::KEY_A, 0));
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+
+        assert_eq!(typed, emitter.0.borrow().len());
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_EQUAL.code(), 1),
+                (Key::KEY_EQUAL.code(), 0),
+                (Key::KEY_LEFTSHIFT.code(), 1),
+                (Key::KEY_DOT.code(), 1),
+                (Key::KEY_DOT.code(), 0),
+                (Key::KEY_LEFTSHIFT.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_follows_the_configured_layout() {
+        let typed = |layout: &str| {
+            let (mut sm, emitter) = state_machine(test_config(&format!(
+                r#"layout = "{}"
+[layers.text]
+fn_key = "KEY_CAPSLOCK"
+[layers.text.keymap]
+KEY_A = {{ text = "@zy" }}
+"#,
+                layout
+            )));
+            sm.settings.timeout = Duration::ZERO;
+            sm.run(key(Key::KEY_CAPSLOCK, 1));
+            sm.run(key(Key::KEY_A, 1));
+            let downs: Vec<u16> = emitter.0.borrow().iter().filter(|(_, v)| *v == 1).map(|(c, _)| *c).collect();
+            assert!(downs.iter().all(|c| emitter.0.borrow().contains(&(*c, 0))));
+            downs
+        };
+
+        assert_eq!(
+            typed("us"),
+            vec![Key::KEY_LEFTSHIFT.code(), Key::KEY_2.code(), Key::KEY_Z.code(), Key::KEY_Y.code()]
+        );
+        assert_eq!(
+            typed("de"),
+            vec![Key::KEY_RIGHTALT.code(), Key::KEY_Q.code(), Key::KEY_Y.code(), Key::KEY_Z.code()]
+        );
+        // Unknown layouts fall back to us
+        assert_eq!(typed("xx"), typed("us"));
+    }
+
+    #[test]
+    fn paused_state_survives_restart() {
+        let path = std::env::temp_dir().join(format!("tokey-paused-{}", std::process::id()));
+        let new = |path: &Path| {
+            StateMachine::new(
+                Box::new(RecordingEmitter::default()),
+                Settings::from_config(test_config("")).unwrap(),
+                Some(path.to_path_buf()),
+                None,
+            )
+        };
+
+        let _ = std::fs::remove_file(&path);
+        let mut sm = new(&path);
+        assert!(!sm.paused);
+        sm.run(key(Key::KEY_RIGHTALT, 1));
+        assert!(new(&path).paused);
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(!new(&path).paused);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn uinput_errors_explain_the_fix() {
+        let missing = DeviceError::Uinput(std::io::Error::from_raw_os_error(nix::libc::ENOENT));
+        let denied = DeviceError::Uinput(std::io::Error::from_raw_os_error(nix::libc::EACCES));
+
+        assert!(missing.to_string().contains("sudo modprobe uinput"));
+        assert!(denied.to_string().contains("sudo usermod -aG input $USER"));
+    }
+
+    #[test]
+    fn grab_errors_tell_busy_from_denied() {
+        let grab_error = |errno| DeviceError::Grab("kbd".to_string(), std::io::Error::from_raw_os_error(errno));
+
+        assert!(grab_error(nix::libc::EBUSY).to_string().contains("another program already grabbed it"));
+        assert!(grab_error(nix::libc::EACCES).to_string().contains("not allowed to"));
+    }
+
+    #[test]
+    fn args_combine_in_any_order() {
+        let parse = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()));
+
+        assert_eq!(
+            parse(&["--monitor", "-c", "conf.toml"]).unwrap(),
+            Args {
+                action: Action::Run,
+                conf_path: Some(PathBuf::from("conf.toml")),
+                conf_dir: None,
+                monitor: true,
+                json: false,
+                replay: None,
+            }
+        );
+        assert_eq!(parse(&["--version"]).unwrap().action, Action::Version);
+        assert_eq!(parse(&["-h", "-v"]).unwrap().action, Action::Help);
+        assert_eq!(parse(&["--bogus"]).unwrap_err(), "unknown option --bogus");
+        assert_eq!(parse(&["-c"]).unwrap_err(), "-c needs a FILE");
+        assert_eq!(
+            parse(&["--config-dir", "/etc/tokey"]).unwrap().conf_dir,
+            Some(PathBuf::from("/etc/tokey"))
+        );
+        assert!(parse(&["--config-dir", "/etc/tokey", "-c", "conf.toml"]).is_err());
+    }
+
+    #[test]
+    fn passthrough_keys_skip_the_layer() {
+        let (mut sm, emitter) = state_machine(test_config(
+            r#"passthrough_keys = ["KEY_LEFTCTRL", "KEY_L"]"#,
+        ));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_LEFTCTRL, 1));
+        sm.run(key(Key::KEY_L, 1));
+        sm.run(key(Key::KEY_L, 0));
+        assert!(matches!(sm.state(), State::DECIDE));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_L, 1));
+        sm.run(key(Key::KEY_L, 0));
+        sm.run(key(Key::KEY_LEFTCTRL, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFTCTRL.code(), 1),
+                (Key::KEY_L.code(), 1),
+                (Key::KEY_L.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_L.code(), 1),
+                (Key::KEY_L.code(), 0),
+                (Key::KEY_LEFTCTRL.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn oneshot_tap_maps_only_the_next_key() {
+        let (mut sm, emitter) = state_machine(test_config("oneshot = true"));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert!(matches!(sm.state(), State::SHIFT));
+        sm.run(key(Key::KEY_J, 1));
+        assert!(matches!(sm.state(), State::IDLE));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_J.code(), 1),
+                (Key::KEY_J.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn oneshot_is_cancelled_by_another_fn_tap() {
+        let (mut sm, emitter) = state_machine(test_config("oneshot = true"));
+        for _ in 0..2 {
+            sm.run(key(Key::KEY_SPACE, 1));
+            sm.run(key(Key::KEY_SPACE, 0));
+        }
+        assert!(matches!(sm.state(), State::IDLE));
+        sm.run(key(Key::KEY_J, 1));
+
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_J.code(), 1)]);
+    }
+
+    #[test]
+    fn decide_timeout_keeps_shift_combo_together() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.settings.timeout = Duration::from_millis(20);
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_LEFTSHIFT, 1));
+        sm.run(key(Key::KEY_A, 1));
+        std::thread::sleep(Duration::from_millis(30));
+        sm.run(key(Key::KEY_A, 0));
+        sm.run(key(Key::KEY_LEFTSHIFT, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFTSHIFT.code(), 1),
+                (Key::KEY_A.code(), 1),
+                (Key::KEY_A.code(), 0),
+                (Key::KEY_LEFTSHIFT.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn fn_release_lets_go_of_buffered_keys_in_reverse() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.settings.timeout = Duration::from_millis(20);
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_J, 1));
+        std::thread::sleep(Duration::from_millis(30));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_A.code(), 1),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_A.code(), 0),
+            ]
+        );
+    }
+
+    #[cfg(feature = "x11_profiles")]
+    #[test]
+    fn window_class_picks_profile_keymap() {
+        let (mut sm, emitter) = state_machine(test_config(
+            r#"[profiles.firefox]
+KEY_J = "KEY_BACK"
+"#,
+        ));
+        sm.settings.timeout = Duration::ZERO;
+        let tap_j = |sm: &mut StateMachine| {
+            sm.run(key(Key::KEY_SPACE, 1));
+            sm.run(key(Key::KEY_J, 1));
+            sm.run(key(Key::KEY_J, 0));
+            sm.run(key(Key::KEY_SPACE, 0));
+        };
+        sm.set_window_class("Firefox".to_string());
+        tap_j(&mut sm);
+        sm.set_window_class("kitty".to_string());
+        tap_j(&mut sm);
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_BACK.code(), 1),
+                (Key::KEY_BACK.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+            ]
+        );
+        assert_eq!(
+            x11_profiles::parse_window_id("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007"),
+            Some("0x3a00007".to_string())
+        );
+        assert_eq!(x11_profiles::parse_window_id("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x0"), None);
+        assert_eq!(
+            x11_profiles::parse_wm_class("WM_CLASS(STRING) = \"Navigator\", \"firefox\"\n"),
+            Some("firefox".to_string())
+        );
+    }
+
+    #[test]
+    fn stats_count_how_holds_resolved() {
+        let (mut sm, _) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_SPACE, 0));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_L, 1));
+        sm.run(key(Key::KEY_L, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        sm.settings.timeout = Duration::ZERO;
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_A, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            sm.stats,
+            Stats {
+                taps: 1,
+                shifts: 2,
+                timeouts: 1,
+                keys_remapped: 2,
+            }
+        );
+    }
+
+    // Repeats here come from the source devices, as forwarded with virtual_autorepeat = false
+    // or a device without EV_REP. The virtual keyboard's own repeats never reach the state machine
+    #[test]
+    fn decide_repeats_keys_pressed_before_fn_key() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_A, 2));
+        sm.run(key(Key::KEY_J, 2));
+        sm.run(key(Key::KEY_SPACE, 2));
+        sm.run(key(Key::KEY_A, 7));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::KEY_A.code(), 1), (Key::KEY_A.code(), 2)]
+        );
+    }
+
+    #[test]
+    fn aliases_resolve_in_keymaps() {
+        let config = test_config(
+            r#"[aliases]
+nav_left = "KEY_J"
+go_left = "KEY_LEFT"
+"#,
+        );
+        let mut config_keymap = config.keymap.clone();
+        config_keymap.insert("nav_left".to_string(), "KEY_LEFTCTRL+go_left".into());
+        let settings = Settings::from_config(Config { keymap: config_keymap, ..config }).unwrap();
+        let chord = match &settings.layers[0].keymap[&Key::KEY_J.code()].output {
+            Output::Chord(chord) => chord.clone(),
+            _ => panic!("not a chord"),
+        };
+        assert_eq!(chord, vec![Key::KEY_LEFTCTRL.code(), Key::KEY_LEFT.code()]);
+
+        let keymap: toml::value::Table = toml::from_str(r#"nav_up = "KEY_UP""#).unwrap();
+        let err = get_keymap(keymap, &layout::US, &Aliases::new()).err().unwrap();
+        assert!(err.contains("undefined alias \"nav_up\""));
+    }
+
+    #[test]
+    fn release_of_key_pressed_before_fn_key() {
+        for (remap, expected) in [
+            (false, vec![(Key::KEY_J.code(), 1), (Key::KEY_J.code(), 0)]),
+            (
+                true,
+                vec![
+                    (Key::KEY_J.code(), 1),
+                    (Key::KEY_J.code(), 0),
+                    (Key::KEY_LEFT.code(), 1),
+                    (Key::KEY_LEFT.code(), 0),
+                ],
+            ),
+        ] {
+            let (mut sm, emitter) = state_machine(test_config(&format!("remap_prefn_release = {}", remap)));
+            sm.run(key(Key::KEY_J, 1));
+            sm.run(key(Key::KEY_SPACE, 1));
+            sm.run(key(Key::KEY_J, 0));
+
+            assert_eq!(*emitter.0.borrow(), expected);
+            assert!(sm.state() == if remap { State::SHIFT } else { State::DECIDE });
+        }
+    }
+
+    #[test]
+    fn disabled_mappings_pass_through() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.set_mapping_enabled(Key::KEY_J.code(), false);
+        for (k, v) in [(Key::KEY_SPACE, 1), (Key::KEY_J, 1), (Key::KEY_J, 0), (Key::KEY_L, 1), (Key::KEY_L, 0)] {
+            sm.run(key(k, v));
+        }
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_J.code(), 1),
+                (Key::KEY_J.code(), 0),
+                (Key::KEY_RIGHT.code(), 1),
+                (Key::KEY_RIGHT.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn scancodes_go_out_with_their_keys() {
+        let (mut sm, emitter) = state_machine(test_config("emit_scancode = true"));
+        let scan = |value| InputEvent::new(evdev::EventType::MISC, evdev::MiscType::MSC_SCAN.0, value);
+        sm.forward(scan(0x70050));
+        sm.run(key(Key::KEY_LEFT, 1));
+        sm.forward(scan(0x70050));
+        sm.run(key(Key::KEY_LEFT, 0));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.forward(scan(0x7000d));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+
+        let msc = (evdev::EventType::MISC.0, evdev::MiscType::MSC_SCAN.0, 0x70050);
+        assert_eq!(*emitter.1.borrow(), vec![msc, msc, msc, msc]);
+        assert_eq!(emitter.0.borrow().len(), 4);
+    }
+
+    #[test]
+    fn replay_reads_evemu_and_csv() {
+        let events = replay::parse(
+            "# EVEMU 1.3\n\
+             N: Keychron C1\n\
+             E: 1.500000 0004 0004 458756\t# EV_MSC / MSC_SCAN             458756\n\
+             E: 1.500000 0001 001e 0001\t# EV_KEY / KEY_A                1\n\
+             1,30,0,1.75\n",
+        )
+        .unwrap();
+        let events: Vec<_> = events
+            .iter()
+            .map(|(at, ev)| (*at, ev.event_type().0, ev.code(), ev.value()))
+            .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                (Duration::ZERO, 4, 4, 458756),
+                (Duration::ZERO, 1, Key::KEY_A.code(), 1),
+                (Duration::from_millis(250), 1, Key::KEY_A.code(), 0),
+            ]
+        );
+        assert_eq!(replay::parse("1,30,1").unwrap_err(), "line 1: expected type,code,value,seconds, got \"1,30,1\"");
+    }
+
+    #[test]
+    fn sequences_match_or_fall_back() {
+        let config = r#"[sequences]
+"KEY_G KEY_G" = "KEY_LEFTCTRL+KEY_HOME"
+"#;
+        let (mut sm, emitter) = state_machine(test_config(config));
+        for (k, v) in [
+            (Key::KEY_SPACE, 1), (Key::KEY_SPACE, 0),
+            (Key::KEY_G, 1), (Key::KEY_G, 0), (Key::KEY_G, 1), (Key::KEY_G, 0),
+        ] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFTCTRL.code(), 1),
+                (Key::KEY_HOME.code(), 1),
+                (Key::KEY_HOME.code(), 0),
+                (Key::KEY_LEFTCTRL.code(), 0),
+            ]
+        );
+        assert!(sm.state() == State::IDLE);
+
+        let (mut sm, emitter) = state_machine(test_config(config));
+        for (k, v) in [
+            (Key::KEY_SPACE, 1), (Key::KEY_SPACE, 0),
+            (Key::KEY_G, 1), (Key::KEY_G, 0), (Key::KEY_A, 1), (Key::KEY_A, 0),
+        ] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+                (Key::KEY_G.code(), 1),
+                (Key::KEY_G.code(), 0),
+                (Key::KEY_A.code(), 1),
+                (Key::KEY_A.code(), 0),
+            ]
+        );
+        assert!(sm.state() == State::IDLE);
+    }
+
+    #[test]
+    fn old_configs_hear_about_new_options() {
+        assert!(config_additions(0).contains(&"layers"));
+        assert!(config_additions(CONFIG_VERSION).is_empty());
+        let config: Config = toml::from_str(default_conf!()).unwrap();
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+    }
+
+    #[test]
+    fn keymaps_take_numeric_keycodes() {
+        let keymap: toml::value::Table = toml::from_str(
+            r#""code:36" = 105
+37 = "KEY_LEFTCTRL+code:106"
+"#,
+        )
+        .unwrap();
+        let keymap = get_keymap(keymap, &layout::US, &Aliases::new()).unwrap();
+        let chord = |code: u16| match &keymap[&code].output {
+            Output::Chord(chord) => chord.clone(),
+            _ => panic!("not a chord"),
+        };
+        assert_eq!(chord(Key::KEY_J.code()), vec![Key::KEY_LEFT.code()]);
+        assert_eq!(chord(Key::KEY_K.code()), vec![Key::KEY_LEFTCTRL.code(), Key::KEY_RIGHT.code()]);
+
+        let keymap: toml::value::Table = toml::from_str(r#"KEY_J = "code:768""#).unwrap();
+        let err = get_keymap(keymap, &layout::US, &Aliases::new()).err().unwrap();
+        assert!(err.contains("keycode 768 is out of range 1-767"));
+    }
+
+    #[test]
+    fn pausing_releases_held_mapped_keys() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_L, 1));
+        sm.toggle_paused();
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_RIGHT.code(), 1),
+                (Key::KEY_RIGHT.code(), 0),
+            ]
+        );
+        assert!(sm.state() == State::IDLE);
+        assert!(sm.layers[0].event_buffer.is_empty());
+    }
+
+    #[test]
+    fn layers_cant_map_their_own_fn_key() {
+        let mut config = test_config("");
+        config.keymap.insert("KEY_SPACE".to_string(), "KEY_ENTER".into());
+        let err = Settings::from_config(config).err().unwrap();
+
+        assert_eq!(err, "Layer default maps its own fn_key KEY_SPACE, pick another fn_key or remove the mapping");
+    }
+
+    #[test]
+    fn resolve_on_down_presses_mapped_key_right_away() {
+        for resolve_on_down in [false, true] {
+            let (mut sm, emitter) = state_machine(test_config(&format!("resolve_on_down = {}", resolve_on_down)));
+            sm.run(key(Key::KEY_SPACE, 1));
+            sm.run(key(Key::KEY_J, 1));
+            let pressed = emitter.0.borrow().clone();
+            sm.run(key(Key::KEY_J, 0));
+            sm.run(key(Key::KEY_SPACE, 0));
+
+            if resolve_on_down {
+                assert_eq!(pressed, vec![(Key::KEY_LEFT.code(), 1)]);
+            } else {
+                assert!(pressed.is_empty());
+            }
+            assert_eq!(
+                *emitter.0.borrow(),
+                vec![(Key::KEY_LEFT.code(), 1), (Key::KEY_LEFT.code(), 0)]
+            );
+            assert!(sm.state() == State::IDLE);
+        }
+    }
+
+    #[test]
+    fn sticky_modifier_applies_to_every_mapped_key() {
+        let config = test_config("");
+        let mut config_keymap = config.keymap.clone();
+        let sticky: toml::Value = toml::from_str(
+            r#"KEY_S = { sticky = "KEY_LEFTSHIFT" }
+KEY_C = { sticky = "clear" }
+"#,
+        )
+        .unwrap();
+        for (k, v) in sticky.as_table().unwrap() {
+            config_keymap.insert(k.clone(), v.clone());
+        }
+        let (mut sm, emitter) = state_machine(Config { keymap: config_keymap, ..config });
+        for (k, v) in [
+            (Key::KEY_SPACE, 1), (Key::KEY_S, 1), (Key::KEY_S, 0),
+            (Key::KEY_J, 1), (Key::KEY_J, 0), (Key::KEY_J, 1), (Key::KEY_J, 0),
+            (Key::KEY_C, 1), (Key::KEY_C, 0), (Key::KEY_J, 1), (Key::KEY_J, 0),
+            (Key::KEY_SPACE, 0),
+        ] {
+            sm.run(key(k, v));
+        }
+        let shifted_left = [
+            (Key::KEY_LEFTSHIFT.code(), 1),
+            (Key::KEY_LEFT.code(), 1),
+            (Key::KEY_LEFT.code(), 0),
+            (Key::KEY_LEFTSHIFT.code(), 0),
+        ];
+        let mut expected = [shifted_left, shifted_left].concat();
+        // Clearing lifts the sticky shift again
+        expected.push((Key::KEY_LEFTSHIFT.code(), 0));
+        expected.extend([(Key::KEY_LEFT.code(), 1), (Key::KEY_LEFT.code(), 0)]);
+        assert_eq!(*emitter.0.borrow(), expected);
+        assert!(sm.state() == State::IDLE);
+    }
+
+    #[test]
+    fn conditional_mapping_follows_held_modifier() {
+        let config = || {
+            let config = test_config("");
+            let mut config_keymap = config.keymap.clone();
+            let conditional: toml::Value =
+                toml::from_str(r#"KEY_P = { default = "KEY_BACKSPACE", with_ctrl = "KEY_DELETE" }"#).unwrap();
+            config_keymap.insert("KEY_P".to_string(), conditional["KEY_P"].clone());
+            Config { keymap: config_keymap, ..config }
+        };
+
+        let (mut sm, emitter) = state_machine(config());
+        for (k, v) in [(Key::KEY_SPACE, 1), (Key::KEY_P, 1), (Key::KEY_P, 0), (Key::KEY_SPACE, 0)] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::KEY_BACKSPACE.code(), 1), (Key::KEY_BACKSPACE.code(), 0)]
+        );
+
+        // Ctrl let go before the key still releases what it was pressed as
+        let (mut sm, emitter) = state_machine(config());
+        for (k, v) in [
+            (Key::KEY_LEFTCTRL, 1), (Key::KEY_SPACE, 1), (Key::KEY_J, 1), (Key::KEY_J, 0),
+            (Key::KEY_P, 1), (Key::KEY_LEFTCTRL, 0), (Key::KEY_P, 0), (Key::KEY_SPACE, 0),
+        ] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFTCTRL.code(), 1),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_DELETE.code(), 1),
+                (Key::KEY_LEFTCTRL.code(), 0),
+                (Key::KEY_DELETE.code(), 0),
+            ]
+        );
+        assert!(sm.conditional_held.is_empty());
+    }
+
+    #[test]
+    fn devices_without_keys_get_a_keyboard() {
+        let mut keys = evdev::AttributeSet::<Key>::new();
+        declare_default_keys(&mut keys);
+        for key in [Key::KEY_ESC, Key::KEY_A, Key::KEY_SPACE, Key::KEY_LEFTMETA, Key::KEY_F12] {
+            assert!(keys.contains(key));
+        }
+        assert!(!keys.contains(Key::BTN_LEFT));
+    }
+
+    #[test]
+    fn device_keymap_applies_to_its_own_keys() {
+        let (mut sm, emitter) = state_machine(test_config("[device_keymaps.Numpad]\nKEY_J = \"KEY_HOME\""));
+        sm.set_source(Some("Numpad"));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        // The release maps like the press, whichever device reports it
+        sm.set_source(Some("Laptop"));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_HOME.code(), 1),
+                (Key::KEY_HOME.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+            ]
+        );
+        assert!(sm.key_devices.is_empty());
+    }
+
+    #[test]
+    fn mode_switch_timeout_is_forgiving() {
+        let timeout = |line: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"{}
+device_name = ""
+fn_key = "KEY_SPACE"
+pause_key = "KEY_RIGHTALT"
+[keymap]
+"#,
+                line
+            ))
+            .unwrap();
+            Settings::from_config(config).map(|settings| settings.timeout)
+        };
+        assert_eq!(timeout(""), Ok(Duration::from_millis(200)));
+        assert_eq!(timeout("mode_switch_timeout = 150.9"), Ok(Duration::from_millis(150)));
+        assert_eq!(
+            timeout("mode_switch_timeout = 0"),
+            Ok(Duration::from_millis(MIN_MODE_SWITCH_TIMEOUT_MS))
+        );
+        assert!(timeout(r#"mode_switch_timeout = "fast""#).is_err());
+        assert!(timeout("mode_switch_timeout = -5").is_err());
+    }
+
+    #[test]
+    fn caps_word_shifts_letters_until_a_boundary() {
+        let config = test_config("");
+        let mut config_keymap = config.keymap.clone();
+        let caps_word: toml::Value = toml::from_str(r#"KEY_C = { sticky = "caps_word" }"#).unwrap();
+        config_keymap.insert("KEY_C".to_string(), caps_word["KEY_C"].clone());
+        let (mut sm, emitter) = state_machine(Config { keymap: config_keymap, ..config });
+        for (k, v) in [(Key::KEY_SPACE, 1), (Key::KEY_C, 1), (Key::KEY_C, 0), (Key::KEY_SPACE, 0)] {
+            sm.run(key(k, v));
+        }
+        assert!(sm.caps_word);
+        for k in [Key::KEY_F, Key::KEY_O, Key::KEY_O, Key::KEY_SPACE] {
+            sm.run(key(k, 1));
+            sm.run(key(k, 0));
+        }
+        let shifted = |k: Key| {
+            [
+                (Key::KEY_LEFTSHIFT.code(), 1),
+                (k.code(), 1),
+                (k.code(), 0),
+                (Key::KEY_LEFTSHIFT.code(), 0),
+            ]
+        };
+        let mut expected = [shifted(Key::KEY_F), shifted(Key::KEY_O), shifted(Key::KEY_O)].concat();
+        expected.extend([(Key::KEY_SPACE.code(), 1), (Key::KEY_SPACE.code(), 0)]);
+        assert_eq!(*emitter.0.borrow(), expected);
+        assert!(!sm.caps_word);
+    }
+
+    #[test]
+    fn pause_combo_needs_every_key_held() {
+        let config = test_config("");
+        let (mut sm, emitter) = state_machine(Config {
+            pause_key: "KEY_LEFTCTRL+KEY_P".into(),
+            ..config
+        });
+        // P on its own is just typed
+        sm.run(key(Key::KEY_P, 1));
+        sm.run(key(Key::KEY_P, 0));
+        assert!(!sm.paused);
+        for (k, v) in [(Key::KEY_LEFTCTRL, 1), (Key::KEY_P, 1), (Key::KEY_P, 0), (Key::KEY_LEFTCTRL, 0)] {
+            sm.run(key(k, v));
+        }
+        assert!(sm.paused);
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_P.code(), 1),
+                (Key::KEY_P.code(), 0),
+                (Key::KEY_LEFTCTRL.code(), 1),
+                // Released when pausing, the real release then goes out as is
+                (Key::KEY_LEFTCTRL.code(), 0),
+                (Key::KEY_LEFTCTRL.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_buffered_at_fn_tap_are_released_unmapped() {
+        let config = r#"[layers.sym]
+fn_key = "KEY_TAB"
+[layers.sym.keymap]
+KEY_J = "KEY_1"
+KEY_K = "KEY_2"
+"#;
+        let (mut sm, emitter) = state_machine(test_config(config));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_SPACE, 0));
+        sm.run(key(Key::KEY_J, 0));
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+                (Key::KEY_J.code(), 1),
+                (Key::KEY_J.code(), 0),
+            ]
+        );
+
+        // J goes up while a layer that maps it is active
+        let (mut sm, emitter) = state_machine(test_config(config));
+        for (k, v) in [
+            (Key::KEY_TAB, 1), (Key::KEY_K, 1), (Key::KEY_K, 0),
+            (Key::KEY_SPACE, 1), (Key::KEY_J, 1), (Key::KEY_SPACE, 0), (Key::KEY_J, 0),
+            (Key::KEY_TAB, 0),
+        ] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_2.code(), 1),
+                (Key::KEY_2.code(), 0),
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+                (Key::KEY_J.code(), 1),
+                (Key::KEY_J.code(), 0),
+            ]
+        );
+        assert!(sm.raw_held.is_empty());
+    }
+
+    #[test]
+    fn invert_maps_in_idle_and_fn_key_suspends() {
+        let (mut sm, emitter) = state_machine(test_config("invert = true"));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::KEY_LEFT.code(), 1), (Key::KEY_LEFT.code(), 0)]
+        );
+
+        emitter.0.borrow_mut().clear();
+        for (k, v) in [(Key::KEY_SPACE, 1), (Key::KEY_J, 1), (Key::KEY_J, 0), (Key::KEY_SPACE, 0)] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_J.code(), 1), (Key::KEY_J.code(), 0)]);
+        assert!(sm.state() == State::IDLE);
+
+        // Pausing releases what the keymap holds, then everything passes through
+        emitter.0.borrow_mut().clear();
+        sm.run(key(Key::KEY_L, 1));
+        sm.run(key(Key::KEY_RIGHTALT, 1));
+        sm.run(key(Key::KEY_L, 0));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        assert!(sm.paused);
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_RIGHT.code(), 1),
+                (Key::KEY_RIGHT.code(), 0),
+                (Key::KEY_L.code(), 0),
+                (Key::KEY_J.code(), 1),
+                (Key::KEY_J.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn fn_tap_key_replaces_fn_key_tap() {
+        let (mut sm, emitter) = state_machine(test_config(r#"fn_tap_key = "KEY_ESC""#));
+        for (k, v) in [(Key::KEY_SPACE, 1), (Key::KEY_SPACE, 0), (Key::KEY_SPACE, 1), (Key::KEY_J, 1)] {
+            sm.run(key(k, v));
+        }
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_ESC.code(), 1),
+                (Key::KEY_ESC.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn debounce_drops_chatter() {
+        let (mut sm, emitter) = state_machine(test_config("debounce_ms = 20"));
+        // The switch bounces on press and on release
+        for (k, v) in [(Key::KEY_A, 1), (Key::KEY_A, 0), (Key::KEY_A, 1)] {
+            sm.run(key(k, v));
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        for (k, v) in [(Key::KEY_A, 0), (Key::KEY_A, 1), (Key::KEY_A, 0)] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_A.code(), 1), (Key::KEY_A.code(), 0)]);
+    }
+
+    #[test]
+    fn reload_keymap_leaves_other_settings() {
+        let (mut sm, emitter) = state_machine(test_config("double_tap_ms = 300"));
+        let mut config = test_config("double_tap_ms = 50");
+        config.keymap.insert("KEY_J".to_string(), "KEY_HOME".into());
+        sm.reload_keymap(config).unwrap();
+        assert_eq!(sm.settings.double_tap, Some(Duration::from_millis(300)));
+        for (k, v) in [(Key::KEY_SPACE, 1), (Key::KEY_J, 1), (Key::KEY_J, 0), (Key::KEY_SPACE, 0)] {
+            sm.run(key(k, v));
+        }
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(Key::KEY_HOME.code(), 1), (Key::KEY_HOME.code(), 0)]
+        );
+
+        let mut config = test_config("");
+        config.keymap.insert("KEY_SPACE".to_string(), "KEY_A".into());
+        assert!(sm.reload_keymap(config).is_err());
+        assert!(sm.mapping(0, Key::KEY_J.code()).is_some());
+    }
+
+    #[test]
+    fn shown_config_has_defaults_and_reads_back() {
+        let args = parse_args(["--show-config", "-c", "conf.toml"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(args.action, Action::ShowConfig);
+
+        let shown = toml::Value::try_from(test_config("").with_defaults()).unwrap().to_string();
+        assert!(shown.contains("layout = \"us\""));
+        assert!(shown.contains("fn_tap_key = \"KEY_SPACE\""));
+        // Off unless set
+        assert!(!shown.lines().any(|line| line.starts_with("double_tap_ms")));
+        let config: Config = toml::from_str(&shown).unwrap();
+        assert_eq!(config.startup_grace_ms, Some(DEFAULT_STARTUP_GRACE_MS));
+        assert_eq!(config.virtual_autorepeat, Some(true));
+        assert!(Settings::from_config(config).is_ok());
+    }
+
+    #[test]
+    fn quick_fn_taps_start_clean() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        for (k, v) in [
+            (Key::KEY_SPACE, 1), (Key::KEY_J, 1), (Key::KEY_SPACE, 0),
+            (Key::KEY_SPACE, 1), (Key::KEY_J, 0), (Key::KEY_SPACE, 0),
+        ] {
+            sm.run(key(k, v));
+            if k == Key::KEY_SPACE && v == 1 {
+                assert!(sm.layers[0].event_buffer.is_empty());
+            }
+        }
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+                (Key::KEY_J.code(), 1),
+                (Key::KEY_J.code(), 0),
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+            ]
+        );
+        assert!(sm.state() == State::IDLE);
+        assert!(sm.layers[0].event_buffer.is_empty());
+    }
+
+    #[test]
+    fn pointer_speeds_up_while_held() {
+        let config = test_config("pointer_speed = 2\npointer_max_speed = 12\npointer_accel_ms = 1000");
+        let settings = Settings::from_config(config).unwrap();
+        assert_eq!(settings.pointer_step(Duration::ZERO), 2);
+        assert_eq!(settings.pointer_step(Duration::from_millis(500)), 7);
+        assert_eq!(settings.pointer_step(Duration::from_millis(1000)), 12);
+        assert_eq!(settings.pointer_step(Duration::from_secs(5)), 12);
+
+        let mut config = test_config("");
+        config.keymap.insert("KEY_H".to_string(), "mouse:left".into());
+        let (mut sm, emitter) = state_machine(config);
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_H, 1));
+        sm.run(key(Key::KEY_H, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        let step = (evdev::EventType::RELATIVE.0, RelativeAxisType::REL_X.0, -2);
+        assert!(emitter.1.borrow().contains(&step));
+        assert!(sm.settings.extra_outputs().buttons.contains(&Key::BTN_LEFT));
+    }
+
+    // Fails its writes with the queued errors before recording them
+    #[derive(Default)]
+    struct FailingEmitter {
+        errors: Vec<std::io::ErrorKind>,
+        emitted: Vec<(u16, i32)>,
+    }
+
+    impl KeyEmitter for FailingEmitter {
+        fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+            if let Some(kind) = self.errors.pop() {
+                return Err(kind.into());
+            }
+            self.emitted.push((code, value));
+            Ok(())
+        }
+
+        fn emit_event(&mut self, _event: InputEvent) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn emit_errors_are_not_fatal() {
+        let mut emitter = FailingEmitter { errors: vec![std::io::ErrorKind::Interrupted], ..Default::default() };
+        send_key_down(&mut emitter, Key::KEY_A.code());
+        assert_eq!(emitter.emitted, vec![(Key::KEY_A.code(), 1)]);
+
+        // Only retried once, then the event is dropped
+        let mut emitter = FailingEmitter {
+            errors: vec![std::io::ErrorKind::Interrupted, std::io::ErrorKind::Interrupted],
+            ..Default::default()
+        };
+        send_key_down(&mut emitter, Key::KEY_A.code());
+        assert!(emitter.emitted.is_empty());
+
+        let mut emitter = FailingEmitter { errors: vec![std::io::ErrorKind::Other], ..Default::default() };
+        send_key_down(&mut emitter, Key::KEY_A.code());
+        send_key_up(&mut emitter, Key::KEY_A.code());
+        assert_eq!(emitter.emitted, vec![(Key::KEY_A.code(), 0)]);
+    }
+
+    #[test]
+    fn toggle_layer_flips_on_each_tap() {
+        let (mut sm, emitter) = state_machine(test_config(
+            r#"[layers.nav]
+fn_key = "KEY_CAPSLOCK"
+mode = "toggle"
+[layers.nav.keymap]
+KEY_H = "KEY_HOME"
+"#,
+        ));
+        let nav = sm.settings.layer_for_fn_key(Key::KEY_CAPSLOCK.code()).unwrap();
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+        assert!(sm.layers[nav].state == State::SHIFT);
+
+        // Held long after the tap, still mapped
+        sm.layers[nav].start_time -= Duration::from_secs(5);
+        sm.run(key(Key::KEY_H, 1));
+        sm.run(key(Key::KEY_H, 0));
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_HOME.code(), 1), (Key::KEY_HOME.code(), 0)]);
+
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+        assert!(sm.layers[nav].state == State::IDLE);
+        sm.run(key(Key::KEY_H, 1));
+        assert_eq!(emitter.0.borrow().last(), Some(&(Key::KEY_H.code(), 1)));
+        assert!(!emitter.0.borrow().iter().any(|(code, _)| *code == Key::KEY_CAPSLOCK.code()));
+    }
+
+    #[test]
+    fn passthrough_fn_hold_forwards_fn_key() {
+        let (mut sm, emitter) = state_machine(test_config("passthrough_fn_hold = true"));
+        let space = Key::KEY_SPACE.code();
+        sm.run(key(Key::KEY_SPACE, 1));
+        assert_eq!(*emitter.0.borrow(), vec![(space, 1)]);
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![(space, 1), (Key::KEY_LEFT.code(), 1), (Key::KEY_LEFT.code(), 0), (space, 0)]
+        );
+
+        // A tap goes out once, not again as fn_tap_key
+        emitter.0.borrow_mut().clear();
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert_eq!(*emitter.0.borrow(), vec![(space, 1), (space, 0)]);
+    }
+
+    // Without dbus there's only pause_key, which has to work just the same
+    #[cfg(not(feature = "tokey_ipc"))]
+    #[test]
+    fn builds_without_dbus() {
+        let (command_tx, _commands) = command::channel().unwrap();
+        let emitter = RecordingEmitter::default();
+        let mut sm = StateMachine::new(
+            Box::new(emitter.clone()),
+            Settings::from_config(test_config("")).unwrap(),
+            None,
+            Some(tokey_ipc::Messenger::new(command_tx)),
+        );
+        sm.run(key(Key::KEY_RIGHTALT, 1));
+        sm.run(key(Key::KEY_RIGHTALT, 0));
+        assert!(sm.paused);
+        sm.run(key(Key::KEY_J, 1));
+        assert_eq!(emitter.0.borrow().last(), Some(&(Key::KEY_J.code(), 1)));
+    }
+
+    // Messenger::new needs a session bus, so only the state machine without one
+    #[cfg(feature = "tokey_ipc")]
+    #[test]
+    fn resume_comes_from_logind() {
+        let rule = tokey_ipc::sleep_match_rule();
+        assert_eq!(
+            rule.match_str(),
+            "type='signal',interface='org.freedesktop.login1.Manager',member='PrepareForSleep'"
+        );
+    }
+
+    #[test]
+    fn resume_reopens_devices_and_releases_held_keys() {
+        let (command_tx, commands) = command::channel().unwrap();
+        let (mut sm, emitter) = state_machine(test_config(""));
+        let conf_path = Path::new(STDIN_CONF_PATH);
+        let mut grabbed = true;
+        let mut handle = |sm: &mut StateMachine| {
+            handle_commands(&commands, sm, &mut [], &mut grabbed, conf_path, false, Duration::ZERO)
+        };
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_L, 1));
+        assert!(!handle(&mut sm));
+
+        command_tx.send(Command::Resumed);
+        assert!(handle(&mut sm));
+        assert!(sm.emitter.pressed().is_empty());
+        assert!(emitter.0.borrow().ends_with(&[(Key::KEY_RIGHT.code(), 0), (Key::KEY_A.code(), 0)]));
+        assert!(sm.state() == State::IDLE);
+        assert!(grabbed);
+    }
+
+    #[cfg(feature = "tokey_ipc")]
+    #[test]
+    fn builds_with_dbus() {
+        let (mut sm, _) = state_machine(test_config(""));
+        assert!(sm.messenger.is_none());
+        sm.run(key(Key::KEY_RIGHTALT, 1));
+        sm.run(key(Key::KEY_RIGHTALT, 0));
+        assert!(sm.paused);
+    }
+
+    #[test]
+    fn config_from_stdin() {
+        let conf = "device_name = \"\"\nfn_key = \"KEY_SPACE\"\npause_key = \"KEY_PAUSE\"\n[keymap]\n";
+        assert!(read_stdin_config(conf.as_bytes()).is_ok());
+        assert_eq!(
+            read_stdin_config(" \n".as_bytes()).err().unwrap(),
+            "No config on stdin, pipe one in or pass a file to -c"
+        );
+        let error = read_stdin_config("fn_key = ".as_bytes()).err().unwrap();
+        assert!(error.starts_with("Error parsing config from stdin"));
+        let args = parse_args(["-c", "-"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(args.conf_path, Some(PathBuf::from(STDIN_CONF_PATH)));
+    }
+
+    #[test]
+    fn watchdog_only_counts_time_awake() {
+        let watchdog = watchdog::Watchdog::new();
+        let later = Instant::now() + Duration::from_secs(10);
+        assert_eq!(watchdog.busy_for(later), None);
+        watchdog.busy();
+        assert!(watchdog.busy_for(later).unwrap() >= Duration::from_secs(9));
+        watchdog.waiting();
+        assert_eq!(watchdog.busy_for(later), None);
+    }
+
+    #[test]
+    fn none_swallows_the_key() {
+        let mut config = test_config("");
+        config.keymap.insert("KEY_INSERT".to_string(), "none".into());
+        config.keymap.insert("KEY_DELETE".to_string(), "KEY_RESERVED".into());
+        let (mut sm, emitter) = state_machine(config);
+        sm.run(key(Key::KEY_SPACE, 1));
+        // Decided on release
+        sm.run(key(Key::KEY_INSERT, 1));
+        sm.run(key(Key::KEY_INSERT, 0));
+        // Then pressed and released in SHIFT, with a repeat
+        sm.run(key(Key::KEY_DELETE, 1));
+        sm.run(key(Key::KEY_DELETE, 2));
+        sm.run(key(Key::KEY_DELETE, 0));
+        assert!(emitter.0.borrow().is_empty());
+        assert!(sm.layers[0].event_buffer.is_empty());
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert!(emitter.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn pausing_releases_every_pressed_key() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_L, 1));
+        sm.toggle_paused();
+        let recorded = emitter.0.borrow();
+        let released = &recorded[recorded.len() - 2..];
+        assert!(released.contains(&(Key::KEY_A.code(), 0)));
+        assert!(released.contains(&(Key::KEY_RIGHT.code(), 0)));
+        assert!(sm.emitter.pressed().is_empty());
+    }
+
+    #[test]
+    fn min_hold_drops_brief_touches() {
+        let (mut sm, emitter) = state_machine(test_config("min_hold_ms = 50"));
+        let right = Key::KEY_RIGHT.code();
+        // The first key decides on its release, min_hold only applies in SHIFT
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        assert!(sm.layers[0].state == State::SHIFT);
+        emitter.0.borrow_mut().clear();
+
+        sm.run(key(Key::KEY_L, 1));
+        sm.run(key(Key::KEY_L, 0));
+        assert!(emitter.0.borrow().is_empty());
+
+        sm.run(key(Key::KEY_L, 1));
+        sm.tick();
+        assert!(emitter.0.borrow().is_empty());
+        sm.layers[0].pending_holds[0].1 -= Duration::from_millis(60);
+        sm.tick();
+        assert_eq!(*emitter.0.borrow(), vec![(right, 1)]);
+        sm.run(key(Key::KEY_L, 0));
+        assert_eq!(*emitter.0.borrow(), vec![(right, 1), (right, 0)]);
+        assert!(sm.layers[0].event_buffer.is_empty());
+    }
+
+    #[test]
+    fn ungrabbing_releases_and_resets() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_L, 1));
+        assert!(!set_grabbed(&mut [], &mut sm, false, Duration::ZERO));
+        assert!(emitter.0.borrow().ends_with(&[(Key::KEY_RIGHT.code(), 0), (Key::KEY_A.code(), 0)]));
+        assert!(sm.state() == State::IDLE);
+        assert!(sm.emitter.pressed().is_empty());
+
+        emitter.0.borrow_mut().clear();
+        assert!(set_grabbed(&mut [], &mut sm, true, Duration::ZERO));
+        assert!(emitter.0.borrow().is_empty());
+        sm.run(key(Key::KEY_J, 1));
+        assert_eq!(*emitter.0.borrow(), vec![(Key::KEY_J.code(), 1)]);
+    }
+
+    #[test]
+    fn double_tap_mapping() {
+        let run = |second_tap_after: Option<Duration>| {
+            let mut config = test_config("key_double_tap_ms = 200");
+            let mapping = toml::from_str::<toml::Value>(r#"m = { tap = "KEY_SPACE", double_tap = "KEY_ENTER" }"#);
+            config.keymap.insert("KEY_SEMICOLON".to_string(), mapping.unwrap()["m"].clone());
+            let (mut sm, emitter) = state_machine(config);
+            sm.run(key(Key::KEY_SPACE, 1));
+            sm.run(key(Key::KEY_SEMICOLON, 1));
+            sm.run(key(Key::KEY_SEMICOLON, 0));
+            // Nothing until it's clear whether a second tap follows
+            assert!(emitter.0.borrow().is_empty());
+            if let Some(after) = second_tap_after {
+                sm.pending_tap.as_mut().unwrap().2 -= after;
+                sm.run(key(Key::KEY_SEMICOLON, 1));
+                sm.run(key(Key::KEY_SEMICOLON, 0));
+            }
+            // The window is over for whatever is still waiting
+            if let Some(pending) = sm.pending_tap.as_mut() {
+                pending.2 -= Duration::from_millis(201);
+            }
+            sm.tick();
+            let typed = emitter.0.borrow().clone();
+            typed
+        };
+        let (space, enter) = (Key::KEY_SPACE.code(), Key::KEY_ENTER.code());
+        assert_eq!(run(None), vec![(space, 1), (space, 0)]);
+        assert_eq!(run(Some(Duration::from_millis(50))), vec![(enter, 1), (enter, 0)]);
+        assert_eq!(run(Some(Duration::from_millis(195))), vec![(enter, 1), (enter, 0)]);
+        assert_eq!(run(Some(Duration::from_millis(201))), vec![(space, 1), (space, 0), (space, 1), (space, 0)]);
+    }
+
+    #[test]
+    fn json_monitor_output() {
+        let fn_down = InputEvent::new(evdev::EventType::KEY, Key::KEY_SPACE.code(), 1);
+        assert_eq!(
+            decision_json(Some(&fn_down), &[], "DECIDE"),
+            r#"{"source":"KEY_SPACE","value":1,"action":"held","emitted":[],"state":"DECIDE"}"#
+        );
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\u000a""#);
+        let a = key(Key::KEY_A, 1);
+        assert!(decision_json(Some(&a), &[a], "IDLE").contains(r#""action":"passthrough""#));
+        let left = key(Key::KEY_LEFT, 1);
+        let mapped = decision_json(Some(&key(Key::KEY_J, 1)), &[left], "SHIFT");
+        assert!(mapped.contains(r#""action":"mapped","emitted":[{"code":"KEY_LEFT","value":1}]"#));
+        let timer = decision_json(None, &[left], "IDLE");
+        assert!(timer.starts_with(r#"{"source":null,"value":null,"action":"timer""#));
+        let parse = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()));
+        assert!(parse(&["--monitor", "--format", "json"]).unwrap().json);
+        assert!(parse(&["--format", "json"]).is_err());
+        assert!(parse(&["--monitor", "--format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn reload_releases_keys_held_in_shift() {
+        let (mut sm, emitter) = state_machine(test_config(""));
+        sm.settings.timeout = Duration::ZERO;
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        assert!(sm.state() == State::SHIFT);
+        assert_eq!(sm.emitter.pressed(), vec![Key::KEY_LEFT.code()]);
+
+        sm.reload(test_config("")).unwrap();
+        assert!(sm.emitter.pressed().is_empty());
+        assert!(sm.state() == State::IDLE);
+        assert_eq!(emitter.0.borrow().last(), Some(&(Key::KEY_LEFT.code(), 0)));
+        // The real releases that follow don't press or release anything mapped
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert!(sm.emitter.pressed().is_empty());
+    }
+
+    #[test]
+    fn mouse_button_fn_key() {
+        let mut config = test_config("");
+        config.fn_key = toml::Value::String("BTN_SIDE".to_string());
+        let (mut sm, emitted) = state_machine(config);
+        sm.run(key(Key::BTN_SIDE, 1));
+        assert!(sm.state() == State::DECIDE);
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        assert!(sm.state() == State::SHIFT);
+        sm.run(key(Key::BTN_SIDE, 0));
+        assert!(sm.state() == State::IDLE);
+        // A tap clicks the button
+        sm.run(key(Key::BTN_SIDE, 1));
+        sm.run(key(Key::BTN_SIDE, 0));
+        assert_eq!(
+            *emitted.0.borrow(),
+            vec![
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::BTN_SIDE.code(), 1),
+                (Key::BTN_SIDE.code(), 0),
+            ]
+        );
+        assert!(sm.settings.extra_outputs().buttons.contains(&Key::BTN_SIDE));
+        let keyboard_only = |key: Key| !is_button(key.code());
+        assert_eq!(sm.settings.missing_fn_keys(keyboard_only), vec!["BTN_SIDE (layer default)"]);
+        assert!(sm.settings.missing_fn_keys(|_| true).is_empty());
+    }
+
+    #[test]
+    fn check_lists_config_problems() {
+        let parse = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()));
+        let args = parse(&["--check", "conf.toml"]).unwrap();
+        assert_eq!(args.action, Action::Check);
+        assert_eq!(args.conf_path, Some(PathBuf::from("conf.toml")));
+        assert!(parse(&["--check"]).is_err());
+
+        let mut config = test_config("");
+        config.fn_key = toml::Value::String("KEY_NOPE".to_string());
+        config.device_name = toml::Value::Integer(3);
+        let problems = config_problems(config);
+        assert!(problems.iter().any(|p| p.contains("Invalid fn_key")));
+        assert!(problems.iter().any(|p| p.contains("device_name")));
+        // Logging goes back to stderr afterwards
+        assert!(logging::captured().is_empty());
+    }
+
+    #[test]
+    fn preserve_timestamps_for_single_keys() {
+        // Keys sent with their own time, None for the current time
+        type Stamped = (u16, i32, Option<std::time::SystemTime>);
+        struct TimeEmitter(Rc<RefCell<Vec<Stamped>>>);
+        impl KeyEmitter for TimeEmitter {
+            fn emit(&mut self, code: u16, value: i32) -> std::io::Result<()> {
+                self.0.borrow_mut().push((code, value, None));
+                Ok(())
+            }
+
+            fn emit_event(&mut self, event: InputEvent) -> std::io::Result<()> {
+                self.0.borrow_mut().push((event.code(), event.value(), Some(event.timestamp())));
+                Ok(())
+            }
+        }
+
+        let emitted = Rc::new(RefCell::new(Vec::new()));
+        let mut config = test_config("preserve_timestamps = true");
+        let chord = toml::Value::String("KEY_LEFTCTRL+KEY_C".to_string());
+        config.keymap.insert("KEY_K".to_string(), chord);
+        let mut sm = StateMachine::new(
+            Box::new(TimeEmitter(emitted.clone())),
+            Settings::from_config(config).unwrap(),
+            None,
+            None,
+        );
+        let at = |ms| std::time::UNIX_EPOCH + Duration::from_millis(ms);
+        sm.run(stamped(Key::KEY_A.code(), 1, at(1000)));
+        sm.run(stamped(Key::KEY_SPACE.code(), 1, at(2000)));
+        sm.run(stamped(Key::KEY_J.code(), 1, at(2010)));
+        sm.run(stamped(Key::KEY_J.code(), 0, at(2020)));
+        sm.run(stamped(Key::KEY_J.code(), 1, at(2030)));
+        sm.run(stamped(Key::KEY_K.code(), 1, at(2040)));
+        assert_eq!(
+            *emitted.borrow(),
+            vec![
+                (Key::KEY_A.code(), 1, Some(at(1000))),
+                // Flushed from the buffer when the layer shifted
+                (Key::KEY_LEFT.code(), 1, None),
+                (Key::KEY_LEFT.code(), 0, None),
+                (Key::KEY_LEFT.code(), 1, Some(at(2030))),
+                (Key::KEY_LEFTCTRL.code(), 1, None),
+                (Key::KEY_C.code(), 1, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_options_are_ignored() {
+        let config = test_config("from_the_future = 3\nanother = \"one\"");
+        assert_eq!(config.unknown.keys().collect::<Vec<_>>(), vec!["another", "from_the_future"]);
+        assert!(Settings::from_config(config).is_ok());
+        let problems = config_problems(test_config("from_the_future = 3\nversion = 14"));
+        assert!(problems.iter().any(|p| p.contains("unknown option from_the_future")));
+        let shown = toml::Value::try_from(test_config("from_the_future = 3").with_defaults()).unwrap();
+        assert!(shown.get("from_the_future").is_none());
+    }
+
+    #[test]
+    fn locked_layer_unlocks_when_idle() {
+        let config = test_config("double_tap_ms = 10000\nlock_idle_timeout_ms = 20");
+        let (mut sm, emitter) = state_machine(config);
+        for _ in 0..2 {
+            sm.run(key(Key::KEY_SPACE, 1));
+            sm.run(key(Key::KEY_SPACE, 0));
+        }
+        sm.run(key(Key::KEY_J, 1));
+        sm.tick();
+        assert!(sm.state() == State::SHIFT);
+        assert!(sm.poll_timeout() > 0);
+        std::thread::sleep(Duration::from_millis(30));
+        sm.tick();
+        assert!(sm.state() == State::IDLE);
+        assert_eq!(sm.poll_timeout(), -1);
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors_show_the_line() {
+        let conf = "device_name = \"\"\nfn_key = KEY_SPACE\n";
+        let err = parse_config(conf, "config file conf.toml").err().unwrap();
+        assert!(err.starts_with("Error parsing config file conf.toml: "));
+        assert!(err.contains("line 2 column 10"));
+        assert!(err.ends_with("\n2 | fn_key = KEY_SPACE\n  |          ^"), "{}", err);
+    }
+
+    #[test]
+    fn global_keymap_swaps_keys_in_any_state() {
+        let (mut sm, emitter) = state_machine(test_config(
+            "[global_keymap]\nKEY_CAPSLOCK = \"KEY_ESC\"\nKEY_ESC = \"KEY_CAPSLOCK\"",
+        ));
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_CAPSLOCK, 2));
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+        // Unmapped keys in the layer swap too
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_ESC, 1));
+        assert!(sm.state() == State::SHIFT);
+        sm.run(key(Key::KEY_SPACE, 0));
+        // Released as what it was pressed as, even though the layer is gone
+        sm.run(key(Key::KEY_ESC, 0));
+        assert!(sm.global_held.is_empty());
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_ESC.code(), 1),
+                (Key::KEY_ESC.code(), 2),
+                (Key::KEY_ESC.code(), 0),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_CAPSLOCK.code(), 1),
+                (Key::KEY_CAPSLOCK.code(), 0),
+            ]
+        );
+
+        // A key pressed in IDLE goes up as its swap while the layer decides
+        let config = test_config("[global_keymap]\nKEY_CAPSLOCK = \"KEY_ESC\"");
+        let (mut sm, emitter) = state_machine(config);
+        sm.run(key(Key::KEY_CAPSLOCK, 1));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_CAPSLOCK, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_ESC.code(), 1),
+                (Key::KEY_ESC.code(), 0),
+                (Key::KEY_SPACE.code(), 1),
+                (Key::KEY_SPACE.code(), 0),
+            ]
+        );
+        assert!(sm.emitter.pressed().is_empty());
+
+        // So does a swapped modifier held through DECIDE
+        let config = test_config("[global_keymap]\nKEY_LEFTALT = \"KEY_LEFTMETA\"");
+        let (mut sm, emitter) = state_machine(config);
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_LEFTALT, 1));
+        sm.run(key(Key::KEY_J, 1));
+        sm.run(key(Key::KEY_J, 0));
+        sm.run(key(Key::KEY_LEFTALT, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFTMETA.code(), 1),
+                (Key::KEY_LEFT.code(), 1),
+                (Key::KEY_LEFT.code(), 0),
+                (Key::KEY_LEFTMETA.code(), 0),
+            ]
+        );
+        assert!(sm.emitter.pressed().is_empty());
+
+        let chord = test_config("[global_keymap]\nKEY_A = \"KEY_B+KEY_C\"");
+        let err = Settings::from_config(chord).err().unwrap();
+        assert!(err.contains("Invalid global_keymap entries"));
+    }
+
+    #[test]
+    fn permission_check_explains_every_problem() {
+        let dir = std::env::temp_dir().join(format!("tokey-perms-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let readable = dir.join("event0");
+        std::fs::write(&readable, "").unwrap();
+        let missing = dir.join("event1");
+
+        let devices = [readable.clone(), missing.clone()];
+        assert!(permission_problems(&devices, true, None).is_empty());
+        let problems = permission_problems(&devices, false, Some(&dir.join("uinput")));
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with(&format!("can't read {}", missing.display())));
+        assert!(problems[1].contains("sudo modprobe uinput"));
+        assert_eq!(permission_problems(&[missing], true, None).len(), 1);
+        assert!(permission_advice(&problems).contains("usermod -aG input"));
+        let paths: toml::Value = vec!["/dev/input/event3", "/dev/input/event4"].into();
+        assert_eq!(configured_device_paths(&paths).unwrap().len(), 2);
+        assert!(configured_device_paths(&vec!["/dev/input/event3", "Keychron"].into()).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn escape_combo_held_exits() {
+        let config = test_config("escape_combo = \"KEY_LEFTCTRL+KEY_ESC\"\nescape_hold_ms = 20");
+        let (mut sm, _) = state_machine(config);
+        sm.run(key(Key::KEY_LEFTCTRL, 1));
+        sm.run(key(Key::KEY_ESC, 1));
+        std::thread::sleep(Duration::from_millis(10));
+        // Letting go starts over
+        sm.run(key(Key::KEY_ESC, 0));
+        sm.run(key(Key::KEY_ESC, 1));
+        assert!(!sm.escaped());
+        assert!(sm.poll_timeout() > 0);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(sm.escaped());
+
+        let (sm, _) = state_machine(test_config("escape_hold_ms = 0"));
+        assert_eq!(sm.settings.escape_combo.len(), 3);
+        assert!(sm.settings.escape_hold.is_none());
+    }
+
+    #[test]
+    fn log_file_records_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("tokey-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("decisions.log");
+        let config = test_config(&format!("log_file = \"{}\"", path.display()));
+        let (mut sm, _) = state_machine(config);
+        sm.run(key(Key::KEY_A, 1));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_J, 1));
+        // Dropping the emitter flushes what's buffered
+        sm.set_emitter(Box::new(MonitorEmitter));
+        let logged = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = logged.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("{\"time\":"));
+        let passthrough =
+            r#""source":"KEY_A","value":1,"action":"passthrough","emitted":[{"code":"KEY_A","value":1}]"#;
+        assert!(lines[0].ends_with(&format!("{},\"state\":\"IDLE\"}}", passthrough)));
+
+        let mut log = audit::AuditLog::open(&path, logged.len() as u64 + 10).unwrap();
+        log.write_line("short").unwrap();
+        log.write_line("past the limit").unwrap();
+        drop(log);
+        let rotated = std::fs::read_to_string(dir.join("decisions.log.1")).unwrap();
+        assert!(rotated.ends_with("short\n"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "past the limit\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn adaptive_timeout_follows_typing_speed() {
+        let (mut sm, _) = state_machine(test_config("adaptive_timeout_ms = [80, 400]"));
+        assert_eq!(sm.effective_timeout(), Duration::from_millis(200));
+        let start = Instant::now();
+        for i in 0..10 {
+            sm.note_key_down(start + Duration::from_millis(50 * i));
+        }
+        assert_eq!(sm.effective_timeout(), Duration::from_millis(100));
+        // Even faster bursts stop at the minimum
+        for i in 0..10 {
+            sm.note_key_down(start + Duration::from_millis(500 + 20 * i));
+        }
+        assert_eq!(sm.effective_timeout(), Duration::from_millis(80));
+        // Slow presses stretch it up to the maximum
+        for i in 0..10 {
+            sm.note_key_down(start + Duration::from_millis(1000 + 700 * i));
+        }
+        assert_eq!(sm.effective_timeout(), Duration::from_millis(400));
+
+        let (sm, _) = state_machine(test_config(""));
+        assert_eq!(sm.effective_timeout(), Duration::from_millis(200));
+        assert!(Settings::from_config(test_config("adaptive_timeout_ms = [300, 100]")).is_err());
+    }
+
+    #[test]
+    fn real_shift_release_keeps_selection_chord() {
+        let mut config = test_config("");
+        config.keymap.insert("KEY_U".to_string(), "KEY_HOME".into());
+        let (mut sm, emitter) = state_machine(config);
+        sm.run(key(Key::KEY_LEFTSHIFT, 1));
+        sm.run(key(Key::KEY_SPACE, 1));
+        sm.run(key(Key::KEY_U, 1));
+        // Shift goes up before the buffered key is decided
+        sm.run(key(Key::KEY_LEFTSHIFT, 0));
+        sm.run(key(Key::KEY_U, 0));
+        sm.run(key(Key::KEY_SPACE, 0));
+
+        assert_eq!(
+            *emitter.0.borrow(),
+            vec![
+                (Key::KEY_LEFTSHIFT.code(), 1),
+                (Key::KEY_HOME.code(), 1),
+                (Key::KEY_LEFTSHIFT.code(), 0),
+                (Key::KEY_HOME.code(), 0),
+            ]
+        );
+        assert!(sm.held_modifiers.is_empty());
+        assert!(sm.layers[0].event_buffer.is_empty());
+        assert!(sm.state() == State::IDLE);
+    }
+}
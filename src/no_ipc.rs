@@ -0,0 +1,24 @@
+use crate::command::CommandSender;
+use crate::Stats;
+
+// Stands in for tokey_ipc when built without dbus, pausing only works through pause_key
+// and nothing hears about state changes
+pub struct Messenger;
+
+impl Messenger {
+    pub fn new(_commands: CommandSender) -> Self {
+        Messenger
+    }
+
+    pub fn set_paused(&self, _paused: bool) {}
+
+    pub fn set_grabbed(&self, _grabbed: bool) {}
+
+    pub fn set_stats(&self, _stats: Stats) {}
+
+    pub fn set_state(&self, _state: &str) {}
+}
+
+pub fn watch_sleep(_commands: CommandSender) {
+    warn!("handle_suspend needs dbus, which this tokey was built without");
+}
@@ -0,0 +1,74 @@
+use evdev::{EventType, InputEvent};
+use std::time::Duration;
+
+// Recorded input for --replay. Takes evemu-record output as is, or CSV lines of
+// type,code,value,seconds with decimal type and code. Times are relative to the first event
+
+pub fn parse(contents: &str) -> Result<Vec<(Duration, InputEvent)>, String> {
+    let mut events = Vec::new();
+    let mut start = None;
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        let parsed = match line.strip_prefix("E:") {
+            Some(event) => parse_evemu(event),
+            None if line.is_empty() || line.starts_with('#') || is_evemu_header(line) => continue,
+            None => parse_csv(line),
+        };
+        let (time, event) = parsed.map_err(|e| format!("line {}: {}", n + 1, e))?;
+        let start = *start.get_or_insert(time);
+        if time < start {
+            return Err(format!("line {}: time {} is before the first event", n + 1, time));
+        }
+        events.push((Duration::from_secs_f64(time - start), event));
+    }
+    Ok(events)
+}
+
+// Device description lines like "N: name" or "B: 00 0b 00 ..."
+fn is_evemu_header(line: &str) -> bool {
+    let mut chars = line.chars();
+    matches!((chars.next(), chars.next()), (Some(c), Some(':')) if c.is_ascii_uppercase())
+}
+
+// "0.012345 0001 001e 0001 # EV_KEY / KEY_A 1", type and code in hex
+fn parse_evemu(event: &str) -> Result<(f64, InputEvent), String> {
+    let fields: Vec<&str> = event.split('#').next().unwrap_or("").split_whitespace().collect();
+    match fields[..] {
+        [time, type_, code, value] => Ok((
+            parse_time(time)?,
+            InputEvent::new(
+                EventType(u16::from_str_radix(type_, 16).map_err(|_| format!("invalid type {}", type_))?),
+                u16::from_str_radix(code, 16).map_err(|_| format!("invalid code {}", code))?,
+                parse_value(value)?,
+            ),
+        )),
+        _ => Err(format!("expected time, type, code and value, got \"{}\"", event.trim())),
+    }
+}
+
+// "1,30,1,0.012345"
+fn parse_csv(line: &str) -> Result<(f64, InputEvent), String> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    match fields[..] {
+        [type_, code, value, time] => Ok((
+            parse_time(time)?,
+            InputEvent::new(
+                EventType(type_.parse().map_err(|_| format!("invalid type {}", type_))?),
+                code.parse().map_err(|_| format!("invalid code {}", code))?,
+                parse_value(value)?,
+            ),
+        )),
+        _ => Err(format!("expected type,code,value,seconds, got \"{}\"", line)),
+    }
+}
+
+fn parse_time(time: &str) -> Result<f64, String> {
+    time.parse::<f64>()
+        .ok()
+        .filter(|t| t.is_finite() && *t >= 0.0)
+        .ok_or_else(|| format!("invalid time {}", time))
+}
+
+fn parse_value(value: &str) -> Result<i32, String> {
+    value.parse().map_err(|_| format!("invalid value {}", value))
+}
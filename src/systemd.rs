@@ -0,0 +1,23 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+// sd_notify(3) without libsystemd, a no-op unless started by systemd with Type=notify
+pub fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let path = path.to_string_lossy();
+    // A leading @ means an abstract socket
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    let result = addr.and_then(|addr| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(e) = result {
+        warn!("Can't notify systemd of {}: {}", state, e);
+    }
+}
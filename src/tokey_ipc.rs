@@ -1,67 +1,222 @@
-use dbus::blocking::Connection;
-use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
-use dbus::channel::MatchingReceiver;
+use dbus::blocking::{Connection, SyncConnection};
+use dbus::channel::{MatchingReceiver, Sender};
 use dbus::message::MatchRule;
-use dbus_crossroads::{Crossroads, IfaceBuilder};
+use dbus_crossroads::{Crossroads, IfaceBuilder, MethodErr};
+use evdev::Key;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use crate::command::{Command, CommandSender};
+use crate::Stats;
+
 const DBUS_IFACE_NAME: &str = "com.chronotab.tokey";
 const DBUS_PATH: &str = "/";
 const DBUS_PROP_NAME: &str = "Paused";
+const DBUS_STATE_PROP_NAME: &str = "State";
+const DBUS_GRABBED_PROP_NAME: &str = "Grabbed";
+
+// Data behind the dbus interface, shared between the input and dbus threads
+struct Status {
+    paused: bool,
+    grabbed: bool,
+    state: String,
+    stats: Stats,
+}
+
+type SharedStatus = Arc<Mutex<Status>>;
+
+// Builds the PropertiesChanged signal for a property from its new value
+type ChangedMsgFn = Box<dyn Fn(&dbus::Path, &dyn dbus::arg::RefArg) -> Option<dbus::Message> + Send + Sync>;
 
 pub struct Messenger {
-    conn: Connection
+    conn: Arc<SyncConnection>,
+    status: SharedStatus,
+    paused_changed: ChangedMsgFn,
+    state_changed: ChangedMsgFn,
+    grabbed_changed: ChangedMsgFn,
 }
 
 impl Messenger {
-    pub fn new() -> Self {
-        register_dbus_iface().expect("Cannot register dbus interface");
+    pub fn new(commands: CommandSender) -> Self {
+        let status = Arc::new(Mutex::new(Status {
+            paused: false,
+            grabbed: true,
+            state: "IDLE".to_string(),
+            stats: Stats::default(),
+        }));
         
-        Messenger { conn: Connection::new_session().expect("Cannot create dbus session") }
+        register_dbus_iface(status, commands).expect("Cannot register dbus interface")
     }
     
-    fn get_proxy(&self) -> dbus::blocking::Proxy<&Connection> {
-        self.conn.with_proxy(DBUS_IFACE_NAME, DBUS_PATH, Duration::from_millis(1000))
+    // Signals are sent from the connection owning DBUS_IFACE_NAME so clients can match on it
+    fn emit_changed(&self, changed: &ChangedMsgFn, value: &dyn dbus::arg::RefArg) {
+        if let Some(msg) = changed(&DBUS_PATH.into(), value) {
+            // Flush right away, the dbus thread may be blocked waiting for incoming messages
+            if self.conn.send(msg).is_ok() {
+                self.conn.channel().flush();
+            }
+        }
     }
     
     pub fn set_paused(&self, paused: bool) {
-        self.get_proxy().set(DBUS_IFACE_NAME, DBUS_PROP_NAME, paused).unwrap();
+        self.status.lock().unwrap().paused = paused;
+        self.emit_changed(&self.paused_changed, &paused);
+    }
+    
+    pub fn set_grabbed(&self, grabbed: bool) {
+        self.status.lock().unwrap().grabbed = grabbed;
+        self.emit_changed(&self.grabbed_changed, &grabbed);
+    }
+    
+    // Changes with nearly every key, so no PropertiesChanged for these
+    pub fn set_stats(&self, stats: Stats) {
+        self.status.lock().unwrap().stats = stats;
+    }
+    
+    pub fn set_state(&self, state: &str) {
+        self.status.lock().unwrap().state = state.to_string();
+        self.emit_changed(&self.state_changed, &state.to_string());
     }
 }
 
-fn register_dbus_iface() -> Result<(), Box<dyn std::error::Error>> {
-    let c = Connection::new_session()?;
+fn register_dbus_iface(status: SharedStatus, commands: CommandSender) -> Result<Messenger, Box<dyn std::error::Error>> {
+    let c = Arc::new(SyncConnection::new_session()?);
     c.request_name(DBUS_IFACE_NAME, false, true, false)?;
     
     let mut cr = Crossroads::new();
+    let mut changed_fns = None;
     
-    let token = cr.register(DBUS_IFACE_NAME, |f: &mut IfaceBuilder<bool>| {
-        f.property(DBUS_PROP_NAME)
-            .get(|_, data| Ok(*data))
-            .set(|_, data, value| {
-                *data = value;
-                Ok(Some(value))
-            });
+    let token = cr.register(DBUS_IFACE_NAME, |f: &mut IfaceBuilder<SharedStatus>| {
+        // Writes go through the input thread, which owns the real paused flag and
+        // announces the change once it's applied
+        let set_commands = commands.clone();
+        let paused_changed = f.property(DBUS_PROP_NAME)
+            .get(|_, data| Ok(data.lock().unwrap().paused))
+            .set(move |_, _, value| {
+                set_commands.send(Command::SetPaused(value));
+                Ok(None)
+            })
+            .changed_msg_fn();
+        // IDLE, DECIDE, SHIFT or SEQUENCE, read-only
+        let state_changed = f.property::<String, _>(DBUS_STATE_PROP_NAME)
+            .get(|_, data| Ok(data.lock().unwrap().state.clone()))
+            .changed_msg_fn();
+        // Whether the keyboards are grabbed, read-only, changed with Grab and Ungrab
+        let grabbed_changed = f.property::<bool, _>(DBUS_GRABBED_PROP_NAME)
+            .get(|_, data| Ok(data.lock().unwrap().grabbed))
+            .changed_msg_fn();
+        changed_fns = Some((paused_changed, state_changed, grabbed_changed));
+        // Counters for tuning mode_switch_timeout, read-only
+        f.property::<u64, _>("Taps")
+            .get(|_, data| Ok(data.lock().unwrap().stats.taps))
+            .emits_changed_false();
+        f.property::<u64, _>("Shifts")
+            .get(|_, data| Ok(data.lock().unwrap().stats.shifts))
+            .emits_changed_false();
+        f.property::<u64, _>("Timeouts")
+            .get(|_, data| Ok(data.lock().unwrap().stats.timeouts))
+            .emits_changed_false();
+        f.property::<u64, _>("KeysRemapped")
+            .get(|_, data| Ok(data.lock().unwrap().stats.keys_remapped))
+            .emits_changed_false();
+        // Re-read the config file on the input thread, same as SIGHUP
+        let reload_commands = commands.clone();
+        f.method("Reload", (), (), move |_, _, ()| {
+            reload_commands.send(Command::Reload);
+            Ok(())
+        });
+        // Only swap in the top level keymap, everything else is left as it is
+        let reload_keymap_commands = commands.clone();
+        f.method("ReloadKeymap", (), (), move |_, _, ()| {
+            reload_keymap_commands.send(Command::ReloadKeymap);
+            Ok(())
+        });
+        // Turn a single source key's mapping off and on again, e.g. "KEY_J"
+        let mapping_commands = commands.clone();
+        f.method(
+            "SetMappingEnabled",
+            ("source_key", "enabled"),
+            (),
+            move |_, _, (source_key, enabled): (String, bool)| {
+                let key = Key::from_str(&source_key).map_err(|_| MethodErr::invalid_arg(&source_key))?;
+                mapping_commands.send(Command::SetMappingEnabled(key.code(), enabled));
+                Ok(())
+            },
+        );
+        // Let go of the keyboards so other tools get them raw, and take them back
+        let ungrab_commands = commands.clone();
+        f.method("Ungrab", (), (), move |_, _, ()| {
+            ungrab_commands.send(Command::SetGrabbed(false));
+            Ok(())
+        });
+        let grab_commands = commands.clone();
+        f.method("Grab", (), (), move |_, _, ()| {
+            grab_commands.send(Command::SetGrabbed(true));
+            Ok(())
+        });
+        // Equivalent to pressing pause_key
+        f.method("TogglePause", (), (), move |_, _, ()| {
+            commands.send(Command::TogglePause);
+            Ok(())
+        });
     });
     
-    cr.insert(DBUS_PATH, &[token], false);
+    cr.insert(DBUS_PATH, &[token], status.clone());
     
+    // SyncConnection filters must be Sync, Crossroads isn't
+    let cr = Mutex::new(cr);
     let _ = &c.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
-        cr.handle_message(msg, conn).unwrap();
+        cr.lock().unwrap().handle_message(msg, conn).unwrap();
         true
     }));
     
+    let process_conn = c.clone();
     std::thread::spawn(move || {
         loop {
-            match c.process(Duration::from_millis(1000)) {
+            match process_conn.process(Duration::from_millis(1000)) {
                 Ok(_) => {}
                 Err(err) => {
-                    println!("dbus loop error: {}", err);
+                    error!("dbus loop error: {}", err);
                     break
                 }
             }
         }
     });
     
-    Ok(())
+    let (paused_changed, state_changed, grabbed_changed) = changed_fns.unwrap();
+    Ok(Messenger {
+        conn: c,
+        status,
+        paused_changed,
+        state_changed,
+        grabbed_changed,
+    })
+}
+
+// logind announces sleep with true and wakeup with false
+pub fn sleep_match_rule() -> MatchRule<'static> {
+    MatchRule::new_signal("org.freedesktop.login1.Manager", "PrepareForSleep")
+}
+
+// Listens to logind on the system bus and tells the input thread whenever the system resumes
+pub fn watch_sleep(commands: CommandSender) {
+    std::thread::spawn(move || {
+        if let Err(e) = follow_sleep(commands) {
+            warn!("Can't listen for resume from sleep, handle_suspend is off: {}", e);
+        }
+    });
+}
+
+fn follow_sleep(commands: CommandSender) -> Result<(), dbus::Error> {
+    let conn = Connection::new_system()?;
+    conn.add_match(sleep_match_rule(), move |(start,): (bool,), _, _| {
+        if !start {
+            commands.send(Command::Resumed);
+        }
+        true
+    })?;
+    loop {
+        conn.process(Duration::from_millis(1000))?;
+    }
 }
@@ -0,0 +1,162 @@
+use evdev::{AttributeSetRef, EventType, InputEvent, Key, LedType, MiscType, RelativeAxisType};
+use nix::libc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::{
+    fs::OpenOptionsExt,
+    io::{AsRawFd, RawFd},
+};
+
+// Same uinput setup evdev::uinput does, which can't declare MSC, LED or REP
+
+const UINPUT_PATH: &str = "/dev/uinput";
+const UINPUT_IOCTL_BASE: u8 = b'U';
+
+nix::ioctl_none!(ui_dev_create, UINPUT_IOCTL_BASE, 1);
+nix::ioctl_write_ptr!(ui_dev_setup, UINPUT_IOCTL_BASE, 3, libc::uinput_setup);
+nix::ioctl_write_int!(ui_set_evbit, UINPUT_IOCTL_BASE, 100);
+nix::ioctl_write_int!(ui_set_keybit, UINPUT_IOCTL_BASE, 101);
+nix::ioctl_write_int!(ui_set_relbit, UINPUT_IOCTL_BASE, 102);
+nix::ioctl_write_int!(ui_set_mscbit, UINPUT_IOCTL_BASE, 104);
+nix::ioctl_write_int!(ui_set_ledbit, UINPUT_IOCTL_BASE, 105);
+
+type SetBitFn = unsafe fn(libc::c_int, nix::sys::ioctl::ioctl_param_type) -> nix::Result<libc::c_int>;
+
+pub struct VirtualDeviceBuilder<'a> {
+    file: File,
+    name: &'a str,
+}
+
+impl<'a> VirtualDeviceBuilder<'a> {
+    pub fn new() -> io::Result<Self> {
+        // Readable too, that's where the host's LED changes show up
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(UINPUT_PATH)?;
+        Ok(VirtualDeviceBuilder { file, name: "" })
+    }
+
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = name;
+        self
+    }
+
+    // Declare an event type and every code in `codes`
+    fn with_bits(self, event_type: EventType, set_bit: SetBitFn, codes: impl Iterator<Item = u16>) -> io::Result<Self> {
+        let fd = self.file.as_raw_fd();
+        unsafe {
+            ui_set_evbit(fd, event_type.0 as _)?;
+            for code in codes {
+                set_bit(fd, code as _)?;
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn with_keys(self, keys: &AttributeSetRef<Key>) -> io::Result<Self> {
+        self.with_bits(EventType::KEY, ui_set_keybit, keys.iter().map(|k| k.code()))
+    }
+
+    pub fn with_relative_axes(self, axes: &AttributeSetRef<RelativeAxisType>) -> io::Result<Self> {
+        self.with_bits(EventType::RELATIVE, ui_set_relbit, axes.iter().map(|a| a.0))
+    }
+
+    pub fn with_misc(self, misc: &AttributeSetRef<MiscType>) -> io::Result<Self> {
+        self.with_bits(EventType::MISC, ui_set_mscbit, misc.iter().map(|m| m.0))
+    }
+
+    pub fn with_leds(self, leds: &AttributeSetRef<LedType>) -> io::Result<Self> {
+        self.with_bits(EventType::LED, ui_set_ledbit, leds.iter().map(|l| l.0))
+    }
+
+    // Have the kernel auto-repeat held keys on the virtual device
+    pub fn with_auto_repeat(self) -> io::Result<Self> {
+        unsafe {
+            ui_set_evbit(self.file.as_raw_fd(), EventType::REPEAT.0 as _)?;
+        }
+        Ok(self)
+    }
+
+    pub fn build(self) -> io::Result<VirtualDevice> {
+        let mut setup = libc::uinput_setup {
+            id: libc::input_id {
+                bustype: evdev::BusType::BUS_USB.0,
+                vendor: 0x1234,
+                product: 0x5678,
+                version: 0x111,
+            },
+            name: [0; libc::UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        // The name has to leave room for the terminating nul
+        for (dst, src) in setup.name.iter_mut().zip(self.name.bytes().take(libc::UINPUT_MAX_NAME_SIZE - 1)) {
+            *dst = src as libc::c_char;
+        }
+        unsafe {
+            ui_dev_setup(self.file.as_raw_fd(), &setup)?;
+            ui_dev_create(self.file.as_raw_fd())?;
+        }
+        Ok(VirtualDevice { file: self.file })
+    }
+}
+
+pub struct VirtualDevice {
+    file: File,
+}
+
+impl VirtualDevice {
+    // Write the events followed by a SYN_REPORT, uinput only passes them on after one
+    pub fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let syn = InputEvent::new(EventType::SYNCHRONIZATION, 0, 0);
+        let raw: Vec<libc::input_event> = events.iter().chain([&syn]).map(|ev| *ev.as_ref()).collect();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(raw.as_ptr() as *const u8, std::mem::size_of_val(raw.as_slice()))
+        };
+        self.file.write_all(bytes)
+    }
+
+    // Reader for what the host writes back to the device
+    pub fn feedback(&self) -> io::Result<Feedback> {
+        Ok(Feedback {
+            file: self.file.try_clone()?,
+        })
+    }
+}
+
+// Host side events of a virtual device, e.g. Caps Lock turning its LED on
+pub struct Feedback {
+    file: File,
+}
+
+impl Feedback {
+    // Readable whenever the host sent something, meant to be added to an epoll set
+    pub fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    // LED changes since the last call, everything else (force feedback uploads) is ignored
+    pub fn fetch_leds(&mut self) -> io::Result<Vec<InputEvent>> {
+        let mut leds = Vec::new();
+        let mut raw: [libc::input_event; 16] = unsafe { std::mem::zeroed() };
+        loop {
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(raw.as_mut_ptr() as *mut u8, std::mem::size_of_val(&raw))
+            };
+            let n = match self.file.read(bytes) {
+                Ok(0) => break,
+                Ok(n) => n / std::mem::size_of::<libc::input_event>(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            leds.extend(
+                raw[..n]
+                    .iter()
+                    .map(|ev| InputEvent::from(*ev))
+                    .filter(|ev| ev.event_type() == EventType::LED),
+            );
+        }
+        Ok(leds)
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// busy_since while the event loop is waiting for input, being idle is never a stall
+const WAITING: u64 = u64::MAX;
+
+// Shared with the event loop, which marks when it wakes up for input and when it goes back
+// to waiting. Only a loop that stays awake too long counts as stuck, so a quiet keyboard
+// never triggers it
+pub struct Watchdog {
+    start: Instant,
+    // ms after start the loop woke up
+    busy_since: AtomicU64,
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Watchdog { start: Instant::now(), busy_since: AtomicU64::new(WAITING) }
+    }
+
+    pub fn busy(&self) {
+        self.busy_since.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn waiting(&self) {
+        self.busy_since.store(WAITING, Ordering::Relaxed);
+    }
+
+    // How long the loop has been handling input as of now, None while it's waiting
+    pub fn busy_for(&self, now: Instant) -> Option<Duration> {
+        match self.busy_since.load(Ordering::Relaxed) {
+            WAITING => None,
+            since => Some(now.duration_since(self.start).saturating_sub(Duration::from_millis(since))),
+        }
+    }
+}
+
+// Checks the loop twice per interval, warning once per stall and again when it recovers
+pub fn spawn(interval: Duration) -> Arc<Watchdog> {
+    let watchdog = Arc::new(Watchdog::new());
+    let watched = watchdog.clone();
+    std::thread::spawn(move || {
+        let mut stalled = false;
+        loop {
+            std::thread::sleep(interval / 2);
+            let busy = watched.busy_for(Instant::now()).filter(|busy| *busy >= interval);
+            match (busy, stalled) {
+                (Some(busy), false) => warn!("Event loop stuck for {}ms handling input", busy.as_millis()),
+                (None, true) => warn!("Event loop is handling input again"),
+                _ => {}
+            }
+            stalled = busy.is_some();
+        }
+    });
+    watchdog
+}
@@ -0,0 +1,61 @@
+use std::io::{self, BufRead, BufReader};
+use std::process::{Command as Process, Stdio};
+
+use crate::command::{Command, CommandSender};
+
+// Follows the focused window with xprop, which ships with every X11 desktop, and sends
+// its WM_CLASS to the input thread whenever it changes
+pub fn watch(commands: CommandSender) {
+    std::thread::spawn(move || {
+        if let Err(e) = follow_active_window(&commands) {
+            warn!("Can't follow the active window, profiles are disabled: {}", e);
+        }
+    });
+}
+
+fn follow_active_window(commands: &CommandSender) -> io::Result<()> {
+    let mut xprop = Process::new("xprop")
+        .args(["-root", "-spy", "_NET_ACTIVE_WINDOW"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdout = BufReader::new(xprop.stdout.take().unwrap());
+    let mut last_class = None;
+    for line in stdout.lines() {
+        // Windows without a WM_CLASS, and the desktop itself, use the default keymap
+        let class = parse_window_id(&line?).and_then(wm_class).unwrap_or_default();
+        if last_class.as_ref() != Some(&class) {
+            debug!("Active window class: {:?}", class);
+            commands.send(Command::ActiveWindow(class.clone()));
+            last_class = Some(class);
+        }
+    }
+    let status = xprop.wait()?;
+    Err(io::Error::other(format!("xprop exited with {}", status)))
+}
+
+fn wm_class(window_id: String) -> Option<String> {
+    let output = Process::new("xprop")
+        .args(["-id", &window_id, "WM_CLASS"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    parse_wm_class(&String::from_utf8_lossy(&output.stdout))
+}
+
+// "_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007", None when nothing has focus
+pub fn parse_window_id(line: &str) -> Option<String> {
+    let id = line.rsplit_once('#')?.1.trim();
+    let id = id.split(',').next()?.trim();
+    (id != "0x0" && id.starts_with("0x")).then(|| id.to_string())
+}
+
+// WM_CLASS(STRING) = "navigator", "firefox", the class is the last of the two
+pub fn parse_wm_class(output: &str) -> Option<String> {
+    let values = output.split_once('=')?.1;
+    values
+        .rsplit(',')
+        .next()
+        .map(|class| class.trim().trim_matches('"').to_string())
+        .filter(|class| !class.is_empty())
+}